@@ -1,5 +1,6 @@
 use colored::*;
-use hf2::utils::{elf_to_bin, flash_bin, vendor_map};
+use hf2::utils::{elf_to_image, flash_image, uf2, vendor_map, Labels, Progress, VerifyMode};
+use hf2::FamilyId;
 use hidapi::{HidApi, HidDevice};
 use std::path::PathBuf;
 use std::process::{Command, Stdio};
@@ -44,17 +45,8 @@ fn main() {
         .expect("Couldn't find the build result");
 
     // Remove first two args which is the calling application name and the `hf2` command from cargo.
-    let mut args: Vec<_> = std::env::args().skip(2).collect();
-
-    // todo, keep as iter. difficult because we want to filter map remove two items at once.
-    // Remove our args as cargo build does not understand them.
-    let flags = ["--pid", "--vid"].iter();
-    for flag in flags {
-        if let Some(index) = args.iter().position(|x| x == flag) {
-            args.remove(index);
-            args.remove(index);
-        }
-    }
+    // Then remove our args, in either `--flag value` or `--flag=value` form, as cargo build does not understand them.
+    let args = cargo_args(std::env::args().skip(2));
 
     let status = Command::new("cargo")
         .arg("build")
@@ -70,9 +62,20 @@ fn main() {
         exit_with_process_status(status)
     }
 
+    // write a uf2 file instead of flashing, no device needed
+    if let Some(output) = opt.uf2 {
+        println!("    {} {:?}", "Converting".green().bold(), path);
+
+        let blocks = uf2::from_file(path, None, opt.family).unwrap_or_else(|e| exit_with_error(&e));
+        uf2::write(output.clone(), &blocks).unwrap_or_else(|e| exit_with_error(&e));
+
+        println!("    {} {:?}", "Finished".green().bold(), output);
+        return;
+    }
+
     let api = HidApi::new().expect("Couldn't find system usb");
 
-    let d = if let (Some(v), Some(p)) = (opt.vid, opt.pid) {
+    let d = hf2::Device::from(if let (Some(v), Some(p)) = (opt.vid, opt.pid) {
         api.open(v, p)
             .expect("Are you sure device is plugged in and in bootloader mode?")
     } else {
@@ -96,7 +99,7 @@ fn main() {
             }
         }
         device.expect("Are you sure device is plugged in and in bootloader mode?")
-    };
+    });
 
     println!(
         "    {} {:?} {:?}",
@@ -107,15 +110,24 @@ fn main() {
 
     println!("    {} {:?}", "Flashing".green().bold(), path);
 
-    let (binary, address) = elf_to_bin(path).unwrap();
+    let image = elf_to_image(path).unwrap_or_else(|e| exit_with_error(&e));
 
     // Start timer.
     let instant = Instant::now();
 
-    let bininfo = hf2::bin_info(&d).expect("bin_info failed");
+    let bininfo = hf2::bin_info(&d).unwrap_or_else(|e| exit_with_error(&e));
     log::debug!("{:?}", bininfo);
 
-    flash_bin(&binary, address, &bininfo, &d).unwrap();
+    let progress = Progress::new(Labels::Cargo);
+    let report = flash_image(&image, opt.verify, &bininfo, &d, &progress)
+        .unwrap_or_else(|e| exit_with_error(&e));
+    progress.finish();
+    println!(
+        "    {} {} of {} pages already on the device",
+        "Skipped ".green().bold(),
+        report.skipped,
+        report.pages
+    );
 
     // Stop timer.
     let elapsed = instant.elapsed();
@@ -126,6 +138,37 @@ fn main() {
     );
 }
 
+/// Arguments to forward to cargo build, everything but our own flags and their values.
+fn cargo_args(mut args: impl Iterator<Item = String>) -> Vec<String> {
+    let flags = ["--pid", "--vid", "--uf2", "--family", "--verify"];
+
+    let mut forwarded = vec![];
+    while let Some(arg) = args.next() {
+        if flags.contains(&arg.as_str()) {
+            // the value is the next argument
+            args.next();
+        } else if !flags
+            .iter()
+            .any(|flag| arg.starts_with(flag) && arg[flag.len()..].starts_with('='))
+        {
+            forwarded.push(arg);
+        }
+    }
+    forwarded
+}
+
+/// Print an error along with everything that caused it, then exit.
+fn exit_with_error(e: &dyn std::error::Error) -> ! {
+    eprint!("{} {}", "error:".red().bold(), e);
+    let mut source = e.source();
+    while let Some(e) = source {
+        eprint!(": {}", e);
+        source = e.source();
+    }
+    eprintln!();
+    std::process::exit(1)
+}
+
 #[cfg(unix)]
 fn exit_with_process_status(status: std::process::ExitStatus) -> ! {
     use std::os::unix::process::ExitStatusExt;
@@ -147,6 +190,8 @@ fn parse_hex_16(input: &str) -> Result<u16, std::num::ParseIntError> {
     }
 }
 
+// `cargo build` arguments are only parsed here so they can be forwarded as is
+#[allow(dead_code)]
 #[derive(Debug, StructOpt)]
 struct Opt {
     // `cargo build` arguments
@@ -173,4 +218,14 @@ struct Opt {
     pid: Option<u16>,
     #[structopt(name = "vid", long = "vid",  parse(try_from_str = parse_hex_16))]
     vid: Option<u16>,
+
+    /// write a uf2 file here instead of flashing a device
+    #[structopt(name = "uf2", long = "uf2", parse(from_os_str))]
+    uf2: Option<PathBuf>,
+    /// family name like ATSAMD51 to tag the uf2 file with
+    #[structopt(name = "family", long = "family")]
+    family: Option<FamilyId>,
+    /// how to check the device before and after writing, crc, readback or none
+    #[structopt(name = "verify", long = "verify", default_value = "crc")]
+    verify: VerifyMode,
 }
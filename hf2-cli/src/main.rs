@@ -1,8 +1,19 @@
-use hf2::utils::{elf_to_bin, flash_bin, vendor_map, verify_bin};
+use colored::*;
+use hf2::utils::svd::{self, Peripheral, Register};
+use hf2::utils::{
+    chip_id, dump, elf_to_image, flash_image, flash_uf2, gdb, load, save, uf2, vendor_map,
+    verify_image, write_variable, FlashReport, Labels, Progress, Symbols, UtilError, Variable,
+    VerifyMode, VerifyReport,
+};
+use hf2::{FamilyId, Serial};
 use hidapi::{HidApi, HidDevice};
-use std::fs::File;
-use std::io::Read;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, Write};
+use std::net::TcpListener;
 use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
 fn main() {
@@ -10,9 +21,35 @@ fn main() {
 
     let args = Opt::from_args();
 
+    // these don't use the single device opened below
+    match args.cmd {
+        Cmd::convert {
+            path,
+            output,
+            family,
+            address,
+        } => {
+            let blocks =
+                uf2::from_file(path, address, family).unwrap_or_else(|e| exit_with_error(&e));
+            uf2::write(output, &blocks).unwrap_or_else(|e| exit_with_error(&e));
+            println!("Success");
+            return;
+        }
+        Cmd::clone {
+            from,
+            to,
+            address,
+            verify,
+        } => {
+            clone(&from, &to, address, verify);
+            return;
+        }
+        _ => (),
+    }
+
     let api = HidApi::new().expect("Couldn't find system usb");
 
-    let d = if let (Some(v), Some(p)) = (args.vid, args.pid) {
+    let d = hf2::Device::from(if let (Some(v), Some(p)) = (args.vid, args.pid) {
         api.open(v, p)
             .expect("Are you sure device is plugged in and in bootloader mode?")
     } else {
@@ -33,7 +70,7 @@ fn main() {
             }
         }
         device.expect("Are you sure device is plugged in and in bootloader mode?")
-    };
+    });
 
     println!(
         "found {:?} {:?}",
@@ -42,45 +79,271 @@ fn main() {
     );
 
     match args.cmd {
-        Cmd::resetIntoApp => hf2::reset_into_app(&d).unwrap(),
-        Cmd::resetIntoBootloader => hf2::reset_into_bootloader(&d).unwrap(),
+        Cmd::resetIntoApp => hf2::reset_into_app(&d).unwrap_or_else(|e| exit_with_error(&e)),
+        Cmd::resetIntoBootloader => {
+            hf2::reset_into_bootloader(&d).unwrap_or_else(|e| exit_with_error(&e))
+        }
         Cmd::info => info(&d),
         Cmd::bininfo => bininfo(&d),
         Cmd::dmesg => dmesg(&d),
-        Cmd::flash { file, address } => {
-            let binary = get_binary(file);
-            let bininfo = hf2::bin_info(&d).expect("bin_info failed");
+        Cmd::monitor { timestamps, log } => monitor(&d, timestamps, log),
+        Cmd::flash {
+            file,
+            address,
+            verify,
+        } => {
+            let image = load(file, address).unwrap_or_else(|e| exit_with_error(&e));
+            let bininfo = hf2::bin_info(&d).unwrap_or_else(|e| exit_with_error(&e));
+            log::debug!("{:?}", bininfo);
+
+            let progress = Progress::new(Labels::Plain);
+            let report = flash_image(&image, verify, &bininfo, &d, &progress)
+                .unwrap_or_else(|e| exit_with_error(&e));
+            progress.finish();
+            print_report(&report);
+            println!("Success")
+        }
+        Cmd::verify {
+            file,
+            address,
+            verify,
+        } => {
+            let image = load(file, address).unwrap_or_else(|e| exit_with_error(&e));
+            let bininfo = hf2::bin_info(&d).unwrap_or_else(|e| exit_with_error(&e));
+            log::debug!("{:?}", bininfo);
+
+            let progress = Progress::new(Labels::Plain);
+            let report = verify_image(&image, verify, &bininfo, &d, &progress)
+                .unwrap_or_else(|e| exit_with_error(&e));
+            progress.finish();
+
+            if !report.is_ok() {
+                print_mismatches(&report);
+                exit_with_error(&UtilError::ContentsDifferent);
+            }
+            println!("Success")
+        }
+        Cmd::elf { path, verify } => {
+            let image = load(path, None).unwrap_or_else(|e| exit_with_error(&e));
+
+            let bininfo = hf2::bin_info(&d).unwrap_or_else(|e| exit_with_error(&e));
+            log::debug!("{:?}", bininfo);
+
+            let progress = Progress::new(Labels::Plain);
+            let report = flash_image(&image, verify, &bininfo, &d, &progress)
+                .unwrap_or_else(|e| exit_with_error(&e));
+            progress.finish();
+            print_report(&report);
+        }
+        Cmd::uf2 { path, verify } => {
+            let blocks = uf2::read(path).unwrap_or_else(|e| exit_with_error(&e));
+
+            let bininfo = hf2::bin_info(&d).unwrap_or_else(|e| exit_with_error(&e));
             log::debug!("{:?}", bininfo);
 
-            flash_bin(&binary, address, &bininfo, &d).unwrap();
+            let progress = Progress::new(Labels::Plain);
+            let report = flash_uf2(&blocks, verify, &bininfo, &d, &progress)
+                .unwrap_or_else(|e| exit_with_error(&e));
+            progress.finish();
+            print_report(&report);
             println!("Success")
         }
-        Cmd::verify { file, address } => {
-            let binary = get_binary(file);
-            let bininfo = hf2::bin_info(&d).expect("bin_info failed");
+        Cmd::dump {
+            output,
+            address,
+            family,
+        } => {
+            let bininfo = hf2::bin_info(&d).unwrap_or_else(|e| exit_with_error(&e));
             log::debug!("{:?}", bininfo);
 
-            verify_bin(&binary, address, &bininfo, &d).unwrap();
+            let progress = Progress::new(Labels::Plain);
+            let image =
+                dump(address, &bininfo, &d, &progress).unwrap_or_else(|e| exit_with_error(&e));
+            progress.finish();
+
+            save(output, &image, family.or(bininfo.family_id))
+                .unwrap_or_else(|e| exit_with_error(&e));
             println!("Success")
         }
-        Cmd::elf { path } => {
-            let (binary, address) = elf_to_bin(path).unwrap();
+        Cmd::restore {
+            file,
+            address,
+            verify,
+        } => {
+            // raw dumps are placed at address, uf2 and hex dumps carry their own addresses
+            let image = match load(file.clone(), None) {
+                Err(UtilError::MissingAddress) => load(file, Some(address)),
+                loaded => loaded,
+            }
+            .unwrap_or_else(|e| exit_with_error(&e));
+            // and those mustn't reach into the bootloader
+            if let Some(start) = image.address().filter(|start| *start < address) {
+                eprintln!(
+                    "{} dump starts at 0x{:08X}, below the application at 0x{:08X}",
+                    "error:".red().bold(),
+                    start,
+                    address
+                );
+                std::process::exit(1)
+            }
+            let bininfo = hf2::bin_info(&d).unwrap_or_else(|e| exit_with_error(&e));
+            log::debug!("{:?}", bininfo);
 
-            let bininfo = hf2::bin_info(&d).expect("bin_info failed");
+            let progress = Progress::new(Labels::Plain);
+            let report = flash_image(&image, verify, &bininfo, &d, &progress)
+                .unwrap_or_else(|e| exit_with_error(&e));
+            progress.finish();
+            print_report(&report);
+            println!("Success")
+        }
+        Cmd::read {
+            address,
+            length,
+            format,
+            output,
+        } => read(&d, address, length, format, output),
+        Cmd::write { address, words } => write(&d, address, &words),
+        Cmd::gdbserver { elf, port } => gdbserver(&d, elf, port),
+        Cmd::watch {
+            elf,
+            names,
+            interval,
+            csv,
+        } => watch(&d, elf, &names, interval, csv),
+        Cmd::chipid { log } => chipid(&d, log),
+        Cmd::regs {
+            svd,
+            peripheral,
+            register,
+            write,
+        } => regs(&d, svd, peripheral, register, &write),
+        Cmd::set { elf, name, value } => {
+            let bininfo = hf2::bin_info(&d).unwrap_or_else(|e| exit_with_error(&e));
             log::debug!("{:?}", bininfo);
 
-            flash_bin(&binary, address, &bininfo, &d).unwrap();
+            let symbols = Symbols::read(elf).unwrap_or_else(|e| exit_with_error(&e));
+            let variable = symbols
+                .resolve(&name)
+                .unwrap_or_else(|e| exit_with_error(&e));
+            write_variable(&variable, value, &bininfo, &d).unwrap_or_else(|e| exit_with_error(&e));
+            println!(
+                "{} = {} at 0x{:08X}, {} bytes",
+                name, value, variable.address, variable.size
+            );
         }
+        Cmd::convert { .. } | Cmd::clone { .. } => unreachable!(),
     }
 }
 
-fn info(d: &HidDevice) {
-    let info = hf2::info(d).expect("info failed");
+fn print_mismatches(report: &VerifyReport) {
+    println!(
+        "{} of {} pages differ",
+        report.mismatches.len(),
+        report.pages
+    );
+    println!(
+        "{:<12}{:<10}{:<10}{:<12}first difference",
+        "page", "expected", "actual", "differs in"
+    );
+    for mismatch in &report.mismatches {
+        println!(
+            "0x{:08X}  0x{:04X}    0x{:04X}    {:<12}{}",
+            mismatch.target_address,
+            mismatch.expected,
+            mismatch.actual,
+            if mismatch.padding { "padding" } else { "data" },
+            mismatch
+                .first_difference
+                .map_or("-".to_string(), |address| format!("0x{:08X}", address))
+        );
+    }
+}
+
+fn print_report(report: &FlashReport) {
+    println!(
+        "wrote {} pages, skipped {} of {} already on the device",
+        report.written, report.skipped, report.pages
+    );
+}
+
+/// Copy the application flash of one board onto another, both picked by usb serial number.
+fn clone(from: &str, to: &str, address: u32, verify: VerifyMode) {
+    let api = HidApi::new().expect("Couldn't find system usb");
+    let source = open_serial(&api, from);
+    let target = open_serial(&api, to);
+
+    let bininfo = hf2::bin_info(&source).unwrap_or_else(|e| exit_with_error(&e));
+    log::debug!("{:?}", bininfo);
+    let target_bininfo = hf2::bin_info(&target).unwrap_or_else(|e| exit_with_error(&e));
+    log::debug!("{:?}", target_bininfo);
+
+    if bininfo.family_id != target_bininfo.family_id
+        || bininfo.flash_page_size != target_bininfo.flash_page_size
+        || bininfo.flash_num_pages != target_bininfo.flash_num_pages
+    {
+        eprintln!(
+            "{} boards differ, {:?} and {:?}",
+            "error:".red().bold(),
+            bininfo,
+            target_bininfo
+        );
+        std::process::exit(1)
+    }
+
+    let progress = Progress::new(Labels::Plain);
+    let image = dump(address, &bininfo, &source, &progress).unwrap_or_else(|e| exit_with_error(&e));
+    let report = flash_image(&image, verify, &target_bininfo, &target, &progress)
+        .unwrap_or_else(|e| exit_with_error(&e));
+    progress.finish();
+    print_report(&report);
+    println!("Success")
+}
+
+/// Open the known device with the given usb serial number, listing the ones found otherwise.
+fn open_serial(api: &HidApi, serial: &str) -> hf2::Device {
+    let vendor = vendor_map();
+    let known: Vec<_> = api
+        .device_list()
+        .filter(|device_info| {
+            vendor
+                .get(&device_info.vendor_id())
+                .is_some_and(|products| products.contains(&device_info.product_id()))
+        })
+        .collect();
+
+    if let Some(device_info) = known
+        .iter()
+        .find(|device_info| device_info.serial_number() == Some(serial))
+    {
+        return device_info
+            .open_device(api)
+            .expect("Are you sure device is plugged in and in bootloader mode?")
+            .into();
+    }
+
+    eprintln!(
+        "{} no device with serial {}, found",
+        "error:".red().bold(),
+        serial
+    );
+    for device_info in known {
+        eprintln!(
+            "  {:?} {:?} serial {:?}",
+            device_info.manufacturer_string(),
+            device_info.product_string(),
+            device_info.serial_number()
+        );
+    }
+    std::process::exit(1)
+}
+
+fn info(d: &hf2::Device) {
+    let info = hf2::info(d).unwrap_or_else(|e| exit_with_error(&e));
     println!("{:?}", info);
 }
 
-fn bininfo(d: &HidDevice) {
-    let bininfo = hf2::bin_info(d).expect("bin_info failed");
+fn bininfo(d: &hf2::Device) {
+    let bininfo = hf2::bin_info(d).unwrap_or_else(|e| exit_with_error(&e));
     println!(
         "{:?} {:?}kb",
         bininfo,
@@ -88,18 +351,499 @@ fn bininfo(d: &HidDevice) {
     );
 }
 
-fn dmesg(d: &HidDevice) {
+fn dmesg(d: &hf2::Device) {
     // todo, test. not supported on my board
-    let dmesg = hf2::dmesg(d).expect("dmesg failed");
+    let dmesg = hf2::dmesg(d).unwrap_or_else(|e| exit_with_error(&e));
     println!("{:?}", dmesg);
 }
 
-fn get_binary(file: PathBuf) -> Vec<u8> {
-    //shouldnt there be a chunking interator for this?
-    let mut f = File::open(file).unwrap();
-    let mut binary = Vec::new();
-    f.read_to_end(&mut binary).unwrap();
-    binary
+/// How `hf2 read` prints memory.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    /// Offsets, hex bytes and ascii, like hexdump -C
+    Hexdump,
+    /// The bytes as they are
+    Raw,
+    /// Little endian words, four to a line
+    Words,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hexdump" => Ok(Format::Hexdump),
+            "raw" => Ok(Format::Raw),
+            "u32" => Ok(Format::Words),
+            _ => Err(format!(
+                "unknown format {}, expected hexdump, raw or u32",
+                s
+            )),
+        }
+    }
+}
+
+fn read(d: &hf2::Device, address: u32, length: usize, format: Format, output: Option<PathBuf>) {
+    let bininfo = hf2::bin_info(d).unwrap_or_else(|e| exit_with_error(&e));
+    log::debug!("{:?}", bininfo);
+
+    // a word table needs whole words
+    let length = match format {
+        Format::Words => length.div_ceil(4) * 4,
+        _ => length,
+    };
+    let data =
+        hf2::read_memory(d, &bininfo, address, length).unwrap_or_else(|e| exit_with_error(&e));
+
+    let bytes = match format {
+        Format::Hexdump => hexdump(address, &data).into_bytes(),
+        Format::Raw => data,
+        Format::Words => word_table(address, &data).into_bytes(),
+    };
+
+    let result = match output {
+        Some(path) => File::create(path).and_then(|mut file| file.write_all(&bytes)),
+        None => io::stdout().write_all(&bytes),
+    };
+    result.unwrap_or_else(|e| exit_with_error(&e));
+}
+
+/// Write whole words, split into as many WRITE WORDS as max_message_size needs.
+fn write(d: &hf2::Device, address: u32, words: &[u32]) {
+    // an unaligned write would read-modify-write the words around it, which registers may not tolerate
+    if !address.is_multiple_of(4) {
+        eprintln!(
+            "{} address 0x{:08X} isn't word aligned",
+            "error:".red().bold(),
+            address
+        );
+        std::process::exit(1)
+    }
+
+    let bininfo = hf2::bin_info(d).unwrap_or_else(|e| exit_with_error(&e));
+    log::debug!("{:?}", bininfo);
+
+    let data: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    hf2::write_memory(d, &bininfo, address, &data).unwrap_or_else(|e| exit_with_error(&e));
+    println!("Success")
+}
+
+/// Sixteen bytes a line with their address and printable ascii.
+fn hexdump(address: u32, data: &[u8]) -> String {
+    let mut text = String::new();
+    for (i, line) in data.chunks(16).enumerate() {
+        text.push_str(&format!("{:08x} ", address.wrapping_add(i as u32 * 16)));
+        for column in 0..16 {
+            if column == 8 {
+                text.push(' ');
+            }
+            match line.get(column) {
+                Some(byte) => text.push_str(&format!(" {:02x}", byte)),
+                None => text.push_str("   "),
+            }
+        }
+        let ascii: String = line
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        text.push_str(&format!("  |{}|\n", ascii));
+    }
+    text
+}
+
+/// Four little endian words a line with their address.
+fn word_table(address: u32, data: &[u8]) -> String {
+    let mut text = String::new();
+    for (i, line) in data.chunks(16).enumerate() {
+        text.push_str(&format!("0x{:08X}:", address.wrapping_add(i as u32 * 16)));
+        for word in line.chunks(4) {
+            let word = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+            text.push_str(&format!(" 0x{:08X}", word));
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// Serve GDB connections on localhost one after another, until killed.
+fn gdbserver(d: &hf2::Device, elf: Option<PathBuf>, port: u16) {
+    let bininfo = hf2::bin_info(d).unwrap_or_else(|e| exit_with_error(&e));
+    log::debug!("{:?}", bininfo);
+
+    let elf = elf.map(|path| {
+        // fail early on something that isn't an elf
+        elf_to_image(path.clone()).unwrap_or_else(|e| exit_with_error(&e));
+        let data = std::fs::read(&path).unwrap_or_else(|e| exit_with_error(&e));
+        (path.to_string_lossy().into_owned(), data)
+    });
+
+    let listener = TcpListener::bind(("127.0.0.1", port)).unwrap_or_else(|e| exit_with_error(&e));
+    println!("listening for gdb on 127.0.0.1:{}", port);
+
+    for stream in listener.incoming() {
+        let mut stream = stream.unwrap_or_else(|e| exit_with_error(&e));
+        println!("gdb connected");
+        let elf = elf
+            .as_ref()
+            .map(|(path, data)| (path.as_str(), data.as_slice()));
+        match gdb::serve(&mut stream, elf, &bininfo, d) {
+            Ok(()) => println!("gdb disconnected"),
+            Err(e) => eprintln!("{} {}", "warning:".yellow().bold(), e),
+        }
+    }
+}
+
+/// Poll variables by name, printing them when they change, or every sample as csv.
+fn watch(d: &hf2::Device, elf: PathBuf, names: &[String], interval: Duration, csv: bool) {
+    let bininfo = hf2::bin_info(d).unwrap_or_else(|e| exit_with_error(&e));
+    log::debug!("{:?}", bininfo);
+
+    let symbols = Symbols::read(elf).unwrap_or_else(|e| exit_with_error(&e));
+    let variables: Vec<_> = names
+        .iter()
+        .map(|name| {
+            symbols
+                .resolve(name)
+                .unwrap_or_else(|e| exit_with_error(&e))
+        })
+        .collect();
+
+    if csv {
+        println!("time,{}", names.join(","));
+    }
+
+    let start = Instant::now();
+    let mut last: Vec<Option<Vec<u8>>> = vec![None; variables.len()];
+    loop {
+        let time = start.elapsed().as_secs_f32();
+        let mut row = vec![];
+
+        for (i, variable) in variables.iter().enumerate() {
+            let value = hf2::read_memory(d, &bininfo, variable.address, variable.size as usize)
+                .unwrap_or_else(|e| exit_with_error(&e));
+
+            if csv {
+                row.push(format_value(variable, &value));
+            } else if last[i].as_ref() != Some(&value) {
+                println!(
+                    "[{:>10.3}] {} = {}",
+                    time,
+                    names[i],
+                    format_value(variable, &value)
+                );
+            }
+            last[i] = Some(value);
+        }
+
+        if csv {
+            println!("{:.3},{}", time, row.join(","));
+        }
+        thread::sleep(interval);
+    }
+}
+
+/// Print the chip's part, revision and serial, optionally appending them to a csv file.
+fn chipid(d: &hf2::Device, log: Option<PathBuf>) {
+    let bininfo = hf2::bin_info(d).unwrap_or_else(|e| exit_with_error(&e));
+    log::debug!("{:?}", bininfo);
+
+    let id = chip_id(&bininfo, d).unwrap_or_else(|e| exit_with_error(&e));
+    println!("family    {:?}", id.family);
+    println!("part      {}", id.part);
+    println!("revision  {}", id.revision);
+    println!("serial    {}", id.serial);
+    println!("device id 0x{:08X}", id.device_id);
+
+    if let Some(path) = log {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| {
+                writeln!(
+                    file,
+                    "{},{:?},{},{},0x{:08X}",
+                    id.serial, id.family, id.part, id.revision, id.device_id
+                )
+            })
+            .unwrap_or_else(|e| exit_with_error(&e));
+    }
+}
+
+/// Print a peripheral's registers decoded by an SVD file, after writing any fields given.
+fn regs(
+    d: &hf2::Device,
+    svd: Option<PathBuf>,
+    peripheral: Option<String>,
+    register: Option<String>,
+    write: &[String],
+) {
+    let bininfo = hf2::bin_info(d).unwrap_or_else(|e| exit_with_error(&e));
+    log::debug!("{:?}", bininfo);
+
+    let svd = match svd {
+        Some(svd) => svd,
+        None => {
+            eprintln!("{} no --svd file given", "error:".red().bold());
+            if let Some(suggestion) = bininfo.family_id.and_then(svd::suggest) {
+                eprintln!(
+                    "device is {:?}, try {}",
+                    bininfo.family_id.unwrap(),
+                    suggestion
+                );
+            }
+            std::process::exit(1)
+        }
+    };
+    let device = svd::read(svd).unwrap_or_else(|e| exit_with_error(&e));
+
+    let peripheral = match peripheral
+        .as_deref()
+        .and_then(|name| device.peripheral(name))
+    {
+        Some(peripheral) => peripheral,
+        None => {
+            if let Some(name) = peripheral {
+                eprintln!("{} no peripheral {}, found", "error:".red().bold(), name);
+            }
+            for peripheral in &device.peripherals {
+                println!(
+                    "{:<16}0x{:08X}  {}",
+                    peripheral.name,
+                    peripheral.base_address,
+                    peripheral.description.as_deref().unwrap_or_default()
+                );
+            }
+            return;
+        }
+    };
+
+    let registers: Vec<&Register> = match register {
+        Some(name) => match peripheral.register(&name) {
+            Some(register) => vec![register],
+            None => {
+                eprintln!(
+                    "{} no register {} in {}",
+                    "error:".red().bold(),
+                    name,
+                    peripheral.name
+                );
+                std::process::exit(1)
+            }
+        },
+        None if write.is_empty() => peripheral.registers.iter().collect(),
+        None => {
+            eprintln!("{} --write needs a register", "error:".red().bold());
+            std::process::exit(1)
+        }
+    };
+
+    if !write.is_empty() {
+        let fields: Vec<(&str, &str)> = write
+            .iter()
+            .map(|field| {
+                field.split_once('=').unwrap_or_else(|| {
+                    eprintln!(
+                        "{} expected FIELD=VALUE, got {}",
+                        "error:".red().bold(),
+                        field
+                    );
+                    std::process::exit(1)
+                })
+            })
+            .collect();
+        svd::write_fields(registers[0], &fields, &bininfo, d)
+            .unwrap_or_else(|e| exit_with_error(&e));
+    }
+
+    print_registers(peripheral, &registers, &bininfo, d);
+}
+
+/// Registers and their fields as a tree.
+fn print_registers(
+    peripheral: &Peripheral,
+    registers: &[&Register],
+    bininfo: &hf2::BinInfoResponse,
+    d: &hf2::Device,
+) {
+    println!(
+        "{} 0x{:08X} {}",
+        peripheral.name.bold(),
+        peripheral.base_address,
+        peripheral.description.as_deref().unwrap_or_default()
+    );
+
+    for (i, register) in registers.iter().enumerate() {
+        let last_register = i + 1 == registers.len();
+        let (branch, indent) = if last_register {
+            ("└─", "   ")
+        } else {
+            ("├─", "│  ")
+        };
+        let digits = (register.size / 4) as usize;
+
+        if !register.readable() {
+            println!(
+                "{} {} 0x{:08X} not read, {}",
+                branch,
+                register.name,
+                register.address,
+                if register.read_action {
+                    "reading has side effects"
+                } else {
+                    "write only"
+                }
+            );
+            continue;
+        }
+        let value = match svd::read_register(register, bininfo, d) {
+            Ok(value) => value,
+            Err(e) => {
+                println!(
+                    "{} {} 0x{:08X} {}",
+                    branch,
+                    register.name,
+                    register.address,
+                    e.to_string().red()
+                );
+                continue;
+            }
+        };
+        println!(
+            "{} {} 0x{:08X} = 0x{:0digits$X}",
+            branch,
+            register.name,
+            register.address,
+            value,
+            digits = digits
+        );
+
+        for (j, field) in register.fields.iter().enumerate() {
+            let branch = if j + 1 == register.fields.len() {
+                "└─"
+            } else {
+                "├─"
+            };
+            let bits = if field.bit_width == 1 {
+                format!("[{}]", field.bit_offset)
+            } else {
+                format!(
+                    "[{}:{}]",
+                    field.bit_offset + field.bit_width - 1,
+                    field.bit_offset
+                )
+            };
+            let field_value = field.extract(value);
+            println!(
+                "{}{} {} {} = {}{}",
+                indent,
+                branch,
+                field.name,
+                bits,
+                if field.bit_width > 4 {
+                    format!("0x{:X}", field_value)
+                } else {
+                    field_value.to_string()
+                },
+                field
+                    .value_of(field_value)
+                    .map_or(String::new(), |value| format!(" {}", value.name.green()))
+            );
+        }
+    }
+}
+
+/// Values of up to 8 bytes in decimal, signed if the debug info says so, anything larger as hex bytes.
+fn format_value(variable: &Variable, bytes: &[u8]) -> String {
+    match variable.decode(bytes) {
+        Some(value) => value.to_string(),
+        None => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+    }
+}
+
+fn monitor(d: &hf2::Device, timestamps: bool, log: Option<PathBuf>) {
+    let mut log = log.map(|path| {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .expect("couldn't open log file")
+    });
+
+    // stdin gets its own thread so waiting on the keyboard doesn't stall reading the device
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines().map_while(Result::ok) {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let start = Instant::now();
+    // the streams interleave, each has its own partial line
+    let mut stdout_line_start = true;
+    let mut stderr_line_start = true;
+
+    loop {
+        while let Ok(line) = rx.try_recv() {
+            hf2::write_serial(d, format!("{}\n", line).as_bytes())
+                .unwrap_or_else(|e| exit_with_error(&e));
+        }
+
+        let serial = match hf2::read_serial(d).unwrap_or_else(|e| exit_with_error(&e)) {
+            Some(serial) => serial,
+            None => continue,
+        };
+
+        let line_start = match serial.stream {
+            Serial::StdOut => &mut stdout_line_start,
+            Serial::StdErr => &mut stderr_line_start,
+        };
+        let mut text = String::new();
+        for c in String::from_utf8_lossy(&serial.data).chars() {
+            if *line_start && timestamps {
+                text.push_str(&format!("[{:>10.3}] ", start.elapsed().as_secs_f32()));
+            }
+            text.push(c);
+            *line_start = c == '\n';
+        }
+
+        if let Some(file) = log.as_mut() {
+            file.write_all(text.as_bytes())
+                .expect("couldn't write log file");
+        }
+
+        match serial.stream {
+            Serial::StdOut => {
+                print!("{}", text);
+                io::stdout().flush().unwrap();
+            }
+            Serial::StdErr => {
+                eprint!("{}", text.red());
+                io::stderr().flush().unwrap();
+            }
+        }
+    }
+}
+
+/// Print an error along with everything that caused it, then exit.
+fn exit_with_error(e: &dyn std::error::Error) -> ! {
+    eprint!("{} {}", "error:".red().bold(), e);
+    let mut source = e.source();
+    while let Some(e) = source {
+        eprint!(": {}", e);
+        source = e.source();
+    }
+    eprintln!();
+    std::process::exit(1)
 }
 
 fn parse_hex_32(input: &str) -> Result<u32, std::num::ParseIntError> {
@@ -110,6 +854,32 @@ fn parse_hex_32(input: &str) -> Result<u32, std::num::ParseIntError> {
     }
 }
 
+/// Decimal, negative or 0x hex values.
+fn parse_value(input: &str) -> Result<i64, std::num::ParseIntError> {
+    if let Some(stripped) = input.strip_prefix("0x") {
+        i64::from_str_radix(stripped, 16)
+    } else {
+        input.parse::<i64>()
+    }
+}
+
+/// Durations like 100ms, 2s or 500us, plain numbers are milliseconds.
+fn parse_duration(input: &str) -> Result<Duration, String> {
+    let (number, unit) = match input.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => input.split_at(index),
+        None => (input, "ms"),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration {}", input))?;
+    match unit {
+        "us" => Ok(Duration::from_micros(number)),
+        "ms" => Ok(Duration::from_millis(number)),
+        "s" => Ok(Duration::from_secs(number)),
+        _ => Err(format!("invalid duration {}, expected us, ms or s", input)),
+    }
+}
+
 fn parse_hex_16(input: &str) -> Result<u16, std::num::ParseIntError> {
     if let Some(stripped) = input.strip_prefix("0x") {
         u16::from_str_radix(stripped, 16)
@@ -135,26 +905,186 @@ pub enum Cmd {
     ///Return internal log buffer if any. The result is a character array.
     dmesg,
 
-    /// flash binary, note includes a verify and reset into app
+    ///Print serial stdout and stderr from the app running on the device. Lines typed are sent to the device.
+    monitor {
+        /// prefix lines with seconds since monitoring started
+        #[structopt(short = "t", long = "timestamps")]
+        timestamps: bool,
+        /// also append everything received to this file
+        #[structopt(short = "l", long = "log", parse(from_os_str))]
+        log: Option<PathBuf>,
+    },
+
+    /// flash binary, intel hex, s-record or elf, note includes a verify and reset into app
     flash {
         #[structopt(short = "f", name = "file", long = "file")]
         file: PathBuf,
+        /// where a raw binary starts, hex, s-record and elf files carry their own
         #[structopt(short = "a", name = "address", long = "address", parse(try_from_str = parse_hex_32))]
-        address: u32,
+        address: Option<u32>,
+        /// how to check the device before and after writing, crc, readback or none
+        #[structopt(long = "verify", default_value = "crc")]
+        verify: VerifyMode,
     },
 
-    /// verify binary
+    /// verify binary, intel hex, s-record or elf
     verify {
         #[structopt(short = "f", name = "file", long = "file")]
         file: PathBuf,
+        /// where a raw binary starts, hex, s-record and elf files carry their own
         #[structopt(short = "a", name = "address", long = "address", parse(try_from_str = parse_hex_32))]
-        address: u32,
+        address: Option<u32>,
+        /// crc, or readback for a byte exact comparison
+        #[structopt(long = "verify", default_value = "crc")]
+        verify: VerifyMode,
     },
 
-    /// flash elf, note includes a verify and reset into app
+    /// flash elf, or intel hex or s-record, note includes a verify and reset into app
     elf {
         #[structopt(parse(from_os_str))]
         path: PathBuf,
+        /// how to check the device before and after writing, crc, readback or none
+        #[structopt(long = "verify", default_value = "crc")]
+        verify: VerifyMode,
+    },
+
+    /// flash uf2 file, note includes a verify and reset into app
+    uf2 {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        /// how to check the device before and after writing, crc, readback or none
+        #[structopt(long = "verify", default_value = "crc")]
+        verify: VerifyMode,
+    },
+
+    /// read the application flash into a .bin, .uf2 or .hex file
+    dump {
+        #[structopt(short = "o", name = "output", long = "output", parse(from_os_str))]
+        output: PathBuf,
+        /// where the application starts, flash below it holds the bootloader and isn't read
+        #[structopt(short = "a", name = "address", long = "address", parse(try_from_str = parse_hex_32))]
+        address: u32,
+        /// family to tag a uf2 file with, defaults to the device's
+        #[structopt(long = "family")]
+        family: Option<FamilyId>,
+    },
+
+    /// write a dump back, only pages that differ are written
+    restore {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+        /// where the application starts and a .bin dump is placed, nothing below it is written
+        #[structopt(short = "a", name = "address", long = "address", parse(try_from_str = parse_hex_32))]
+        address: u32,
+        /// how to check the device before and after writing, crc, readback or none
+        #[structopt(long = "verify", default_value = "crc")]
+        verify: VerifyMode,
+    },
+
+    /// copy the application flash of one board onto another, picked by usb serial number
+    clone {
+        #[structopt(long = "from")]
+        from: String,
+        #[structopt(long = "to")]
+        to: String,
+        /// where the application starts, the bootloader below it is neither read nor written
+        #[structopt(short = "a", name = "address", long = "address", parse(try_from_str = parse_hex_32))]
+        address: u32,
+        /// how to check the device before and after writing, crc, readback or none
+        #[structopt(long = "verify", default_value = "crc")]
+        verify: VerifyMode,
+    },
+
+    /// read memory, like RAM or peripheral registers, word by word
+    read {
+        #[structopt(short = "a", name = "address", long = "address", parse(try_from_str = parse_hex_32))]
+        address: u32,
+        /// bytes to read, any alignment
+        #[structopt(short = "l", name = "length", long = "length", default_value = "4")]
+        length: usize,
+        /// hexdump, raw, or u32 for a table of words
+        #[structopt(long = "format", default_value = "hexdump")]
+        format: Format,
+        /// write to this file instead of stdout
+        #[structopt(short = "o", name = "output", long = "output", parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+
+    /// write words to memory, like peripheral registers, each written whole
+    write {
+        /// word aligned
+        #[structopt(short = "a", name = "address", long = "address", parse(try_from_str = parse_hex_32))]
+        address: u32,
+        #[structopt(short = "w", name = "words", long = "words", required = true, parse(try_from_str = parse_hex_32))]
+        words: Vec<u32>,
+    },
+
+    /// serve memory to gdb over its remote protocol, no run control, serving the elf for symbols
+    gdbserver {
+        #[structopt(parse(from_os_str))]
+        elf: Option<PathBuf>,
+        #[structopt(long = "port", default_value = "3333")]
+        port: u16,
+    },
+
+    /// print variables from the elf as they change, like COUNTER or STATE.mode
+    watch {
+        #[structopt(parse(from_os_str))]
+        elf: PathBuf,
+        /// symbol names, with fields after dots
+        #[structopt(required = true)]
+        names: Vec<String>,
+        /// time between reads, like 100ms or 1s
+        #[structopt(short = "i", long = "interval", default_value = "100ms", parse(try_from_str = parse_duration))]
+        interval: Duration,
+        /// print every sample as csv, for plotting
+        #[structopt(long = "csv")]
+        csv: bool,
+    },
+
+    /// print the chip's part number, revision and unique serial number
+    chipid {
+        /// also append serial, family, part, revision and device id to this csv file
+        #[structopt(short = "l", long = "log", parse(from_os_str))]
+        log: Option<PathBuf>,
+    },
+
+    /// read a peripheral's registers and decode their fields with an SVD file, lists peripherals without one
+    regs {
+        #[structopt(long = "svd", parse(from_os_str))]
+        svd: Option<PathBuf>,
+        peripheral: Option<String>,
+        /// just this register, like GROUP[0].DIR inside a cluster
+        register: Option<String>,
+        /// set a field of the register first, like PMUXEN=ENABLED or DRVSTR=1, repeatable
+        #[structopt(short = "w", long = "write")]
+        write: Vec<String>,
+    },
+
+    /// write a variable from the elf, like CONFIG.threshold, keeping the memory around it
+    #[structopt(setting = structopt::clap::AppSettings::AllowNegativeNumbers)]
+    set {
+        #[structopt(parse(from_os_str))]
+        elf: PathBuf,
+        /// symbol name, with fields after dots
+        name: String,
+        /// must fit the variable's size, negative values are written as two's complement
+        #[structopt(parse(try_from_str = parse_value))]
+        value: i64,
+    },
+
+    /// convert elf, intel hex, s-record or binary to a uf2 file, no device needed
+    convert {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        #[structopt(short = "o", name = "output", long = "output", parse(from_os_str))]
+        output: PathBuf,
+        /// family name like ATSAMD51, or id like 0x55114460
+        #[structopt(long = "family")]
+        family: Option<FamilyId>,
+        /// where a raw binary starts, hex, s-record and elf files carry their own
+        #[structopt(short = "a", name = "address", long = "address", parse(try_from_str = parse_hex_32))]
+        address: Option<u32>,
     },
 }
 
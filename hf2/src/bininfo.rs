@@ -1,9 +1,10 @@
-use crate::command::{rx, xmit, Command, CommandResponse, CommandResponseStatus};
-use crate::Error;
+use crate::command::{send, Command};
+use crate::{Error, ReadWrite};
 use core::convert::TryFrom;
+use core::str::FromStr;
 use scroll::{ctx, Pread, LE};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum BinInfoMode {
     //bootloader, and thus flashing of user-space programs is allowed
     Bootloader = 0x0001,
@@ -24,18 +25,10 @@ impl TryFrom<u32> for BinInfoMode {
 }
 
 /// This command states the current mode of the device:
-pub fn bin_info(d: &hidapi::HidDevice) -> Result<BinInfoResponse, Error> {
-    xmit(Command::new(0x0001, 0, vec![]), d)?;
-
-    match rx(d) {
-        Ok(CommandResponse {
-            status: CommandResponseStatus::Success,
-            data,
-            ..
-        }) => (data.as_slice()).pread_with(0, LE),
-        Ok(_) => Err(Error::CommandNotRecognized),
-        Err(e) => Err(e),
-    }
+pub fn bin_info(d: &impl ReadWrite) -> Result<BinInfoResponse, Error> {
+    send(Command::new(0x0001, d.hf2_next_tag(), vec![]), d)
+        .and_then(|data| data.as_slice().pread_with(0, LE))
+        .map_err(|e| e.context(0x0001, None))
 }
 
 ///Response to the bin_info command
@@ -76,6 +69,44 @@ impl From<u32> for FamilyId {
     }
 }
 
+impl From<FamilyId> for u32 {
+    fn from(val: FamilyId) -> Self {
+        match val {
+            FamilyId::ATSAMD21 => 0x68ed_2b88,
+            FamilyId::ATSAMD51 => 0x5511_4460,
+            FamilyId::NRF52840 => 0x1b57_745f,
+            FamilyId::STM32F103 => 0x5ee2_1072,
+            FamilyId::STM32F401 => 0x5775_5a57,
+            FamilyId::ATMEGA32 => 0x1657_3617,
+            FamilyId::CYPRESS_FX2 => 0x5a18_069b,
+            FamilyId::UNKNOWN(val) => val,
+        }
+    }
+}
+
+///Parses a family name like ATSAMD51, case insensitive, or a raw id like 0x55114460
+impl FromStr for FamilyId {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let family_id = match s.to_ascii_uppercase().as_str() {
+            "ATSAMD21" => Self::ATSAMD21,
+            "ATSAMD51" => Self::ATSAMD51,
+            "NRF52840" => Self::NRF52840,
+            "STM32F103" => Self::STM32F103,
+            "STM32F401" => Self::STM32F401,
+            "ATMEGA32" => Self::ATMEGA32,
+            "CYPRESS_FX2" => Self::CYPRESS_FX2,
+            other => match other.strip_prefix("0X") {
+                Some(hex) => u32::from_str_radix(hex, 16)
+                    .map_err(|_| format!("invalid family id {}", s))?
+                    .into(),
+                None => return Err(format!("unknown family {}", s)),
+            },
+        };
+        Ok(family_id)
+    }
+}
+
 impl<'a> ctx::TryFromCtx<'a, scroll::Endian> for BinInfoResponse {
     type Error = Error;
     fn try_from_ctx(this: &'a [u8], le: scroll::Endian) -> Result<(Self, usize), Self::Error> {
@@ -111,3 +142,17 @@ impl<'a> ctx::TryFromCtx<'a, scroll::Endian> for BinInfoResponse {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn family_from_str() {
+        assert_eq!("ATSAMD51".parse(), Ok(FamilyId::ATSAMD51));
+        assert_eq!("nrf52840".parse(), Ok(FamilyId::NRF52840));
+        assert_eq!("0x68ed2b88".parse(), Ok(FamilyId::ATSAMD21));
+        assert_eq!("0x1234".parse(), Ok(FamilyId::UNKNOWN(0x1234)));
+        assert!("ATSAMD99".parse::<FamilyId>().is_err());
+    }
+}
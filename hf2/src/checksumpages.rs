@@ -1,10 +1,10 @@
-use crate::command::{rx, xmit, Command, CommandResponse, CommandResponseStatus};
-use crate::Error;
+use crate::command::{send, Command};
+use crate::{Error, ReadWrite};
 use scroll::{ctx, Pread, Pwrite, LE};
 
 ///Compute checksum of a number of pages. Maximum value for num_pages is max_message_size / 2 - 2. The checksum algorithm used is CRC-16-CCITT.
 pub fn checksum_pages(
-    d: &hidapi::HidDevice,
+    d: &impl ReadWrite,
     target_address: u32,
     num_pages: u32,
 ) -> Result<ChecksumPagesResponse, Error> {
@@ -14,17 +14,9 @@ pub fn checksum_pages(
     buffer.gwrite_with(target_address, &mut offset, scroll::LE)?;
     buffer.gwrite_with(num_pages, &mut offset, scroll::LE)?;
 
-    xmit(Command::new(0x0007, 0, buffer), d)?;
-
-    match rx(d) {
-        Ok(CommandResponse {
-            status: CommandResponseStatus::Success,
-            data,
-            ..
-        }) => (data.as_slice()).pread_with(0, LE),
-        Ok(_) => Err(Error::CommandNotRecognized),
-        Err(e) => Err(e),
-    }
+    send(Command::new(0x0007, d.hf2_next_tag(), buffer), d)
+        .and_then(|data| data.as_slice().pread_with(0, LE))
+        .map_err(|e| e.context(0x0007, Some(target_address)))
 }
 
 ///Response to the checksum_pages command
@@ -1,25 +1,17 @@
-use crate::{Error, ReadWrite};
+use crate::{Error, ReadWrite, Serial};
 use core::convert::TryFrom;
+use core::sync::atomic::{AtomicU16, Ordering};
 
 use scroll::{ctx, Pread, Pwrite, LE};
 
-impl From<scroll::Error> for Error {
-    fn from(_err: scroll::Error) -> Self {
-        Error::Parse
-    }
-}
+///Source of tags for transports that don't keep their own sequence
+static TAG: AtomicU16 = AtomicU16::new(0);
 
-impl From<core::str::Utf8Error> for Error {
-    fn from(_err: core::str::Utf8Error) -> Self {
-        Error::Parse
-    }
-}
+///Empty reads, or stale responses, tolerated while waiting for a response before giving up. Callers retry whole commands on top of this.
+const RX_RETRIES: usize = 5;
 
-impl From<std::io::Error> for Error {
-    fn from(_err: std::io::Error) -> Self {
-        Error::Arguments
-    }
-}
+///Most reports drained before sending a command, so a chatty device can't stall us forever
+const DRAIN_LIMIT: usize = 256;
 
 #[derive(Debug, PartialEq)]
 pub(crate) struct CommandResponse {
@@ -55,7 +47,7 @@ impl TryFrom<u8> for CommandResponseStatus {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 enum PacketType {
     //Inner packet of a command message
     Inner = 0,
@@ -133,10 +125,64 @@ impl Command {
     }
 }
 
-///Transmit a Command, command.data should already have been LE converted
+///Split a packet header into its type and length, routing serial packets to the device. Returns None for serial packets as they aren't part of any command.
+fn route(
+    buffer: &[u8],
+    count: usize,
+    d: &impl ReadWrite,
+) -> Result<Option<(PacketType, usize)>, Error> {
+    let ptype = PacketType::try_from(buffer[0] >> 6)?;
+
+    log::debug!("rx ptype: {:?}", ptype);
+
+    let len: usize = (buffer[0] & 0x3F) as usize;
+
+    log::debug!("rx len: {:?}", len);
+
+    if len >= count {
+        return Err(Error::Parse);
+    }
+
+    let stream = match ptype {
+        PacketType::StdOut => Serial::StdOut,
+        PacketType::Stderr => Serial::StdErr,
+        _ => return Ok(Some((ptype, len))),
+    };
+
+    d.hf2_serial(stream, &buffer[1..(len + 1)]);
+
+    Ok(None)
+}
+
+///Next tag from the process wide sequence, wrapping around
+pub(crate) fn next_tag() -> u16 {
+    TAG.fetch_add(1, Ordering::Relaxed)
+}
+
+///Discard any input still pending, such as a response that arrived after its command timed out, so it can't be attributed to the next command.
+pub(crate) fn drain(d: &impl ReadWrite) -> Result<(), Error> {
+    let buffer = &mut [0_u8; 64];
+
+    for _ in 0..DRAIN_LIMIT {
+        let count = d.hf2_read_nonblocking(buffer)?;
+        if count < 1 {
+            break;
+        }
+        // serial is still worth delivering, anything else is stale
+        match route(buffer, count, d) {
+            Ok(None) => (),
+            _ => log::debug!("drained: {:02X?}", &buffer[..count.min(buffer.len())]),
+        }
+    }
+    Ok(())
+}
+
+///Transmit a Command, command.data should already have been LE converted. Pending input is drained first.
 pub(crate) fn xmit(cmd: Command, d: &impl ReadWrite) -> Result<(), Error> {
     log::debug!("{:?}", cmd);
 
+    drain(d)?;
+
     //Packets are up to 64 bytes long + first byte is Report ID,
     let buffer = &mut [0_u8; 65];
 
@@ -188,39 +234,33 @@ pub(crate) fn xmit(cmd: Command, d: &impl ReadWrite) -> Result<(), Error> {
     Ok(())
 }
 
-///Receive a CommandResponse, CommandResponse.data is not interpreted in any way.
-pub(crate) fn rx(d: &impl ReadWrite) -> Result<CommandResponse, Error> {
+///Receive the CommandResponse for tag, CommandResponse.data is not interpreted in any way. Responses with any other tag are stale leftovers of an earlier command and are discarded.
+pub(crate) fn rx(tag: u16, d: &impl ReadWrite) -> Result<CommandResponse, Error> {
     let mut bitsnbytes: Vec<u8> = vec![];
 
     let buffer = &mut [0_u8; 64];
-    let mut retries = 5;
+    let mut retries = RX_RETRIES;
 
-    // keep reading until Final packet
+    // keep reading until Final packet of the response with our tag
     'outer: loop {
         let count = d.hf2_read(buffer)?;
 
         log::debug!("rx count: {:?}", count);
 
         if count < 1 {
-            if retries <= 0 {
-                return Err(Error::Parse);
+            if retries == 0 {
+                return Err(Error::Timeout);
             } else {
                 retries -= 1;
                 continue 'outer;
             }
         }
 
-        let ptype = PacketType::try_from(buffer[0] >> 6)?;
-
-        log::debug!("rx ptype: {:?}", ptype);
-
-        let len: usize = (buffer[0] & 0x3F) as usize;
-
-        log::debug!("rx len: {:?}", len);
-
-        if len >= count {
-            return Err(Error::Parse);
-        }
+        // serial packets are delivered on the side and don't end the response
+        let (ptype, len) = match route(buffer, count, d)? {
+            Some(header) => header,
+            None => continue 'outer,
+        };
 
         log::debug!(
             "rx header: {:02X?} data: {:02X?}",
@@ -231,16 +271,105 @@ pub(crate) fn rx(d: &impl ReadWrite) -> Result<CommandResponse, Error> {
         //skip the header byte and strip excess bytes remote is allowed to send
         bitsnbytes.extend_from_slice(&buffer[1..(len + 1)]);
 
-        if ptype != PacketType::Inner {
-            break;
+        if ptype == PacketType::Inner {
+            continue 'outer;
         }
+
+        let resp: CommandResponse = bitsnbytes.as_slice().pread_with(0, LE)?;
+
+        log::debug!("{:?}", resp);
+
+        if resp.tag == tag {
+            return Ok(resp);
+        }
+
+        log::warn!(
+            "discarding stale response with tag {:#06X}, expected {:#06X}",
+            resp.tag,
+            tag
+        );
+        bitsnbytes.clear();
+
+        if retries == 0 {
+            return Err(Error::Sequence {
+                expected: tag,
+                received: resp.tag,
+            });
+        }
+        retries -= 1;
+    }
+}
+
+///Transmit a Command and receive its response data, a status other than Success is an error
+pub(crate) fn send(cmd: Command, d: &impl ReadWrite) -> Result<Vec<u8>, Error> {
+    let tag = cmd.tag;
+    xmit(cmd, d)?;
+
+    match rx(tag, d)? {
+        CommandResponse {
+            status: CommandResponseStatus::Success,
+            data,
+            ..
+        } => Ok(data),
+        CommandResponse {
+            status: CommandResponseStatus::ParseError,
+            status_info,
+            ..
+        } => Err(Error::CommandNotRecognized { status_info }),
+        CommandResponse {
+            status: CommandResponseStatus::ExecutionError,
+            status_info,
+            ..
+        } => Err(Error::Execution { status_info }),
+    }
+}
+
+///Receive a single report, returning its stream and data if it was serial. None on timeout, or if it belonged to a command instead.
+pub(crate) fn rx_serial(d: &impl ReadWrite) -> Result<Option<(Serial, Vec<u8>)>, Error> {
+    let buffer = &mut [0_u8; 64];
+
+    let count = d.hf2_read(buffer)?;
+    if count < 1 {
+        return Ok(None);
+    }
+
+    let ptype = PacketType::try_from(buffer[0] >> 6)?;
+    let len: usize = (buffer[0] & 0x3F) as usize;
+
+    if len >= count {
+        return Err(Error::Parse);
     }
 
-    let resp = bitsnbytes.as_slice().pread_with(0, LE)?;
+    let stream = match ptype {
+        PacketType::StdOut => Serial::StdOut,
+        PacketType::Stderr => Serial::StdErr,
+        _ => {
+            log::debug!("rx ignoring command packet: {:02X?}", &buffer[..count]);
+            return Ok(None);
+        }
+    };
+
+    Ok(Some((stream, buffer[1..(len + 1)].to_vec())))
+}
+
+///Transmit serial data to the device in chunks up to 63
+pub(crate) fn xmit_serial(stream: Serial, data: &[u8], d: &impl ReadWrite) -> Result<(), Error> {
+    let ptype = match stream {
+        Serial::StdOut => PacketType::StdOut,
+        Serial::StdErr => PacketType::Stderr,
+    };
 
-    log::debug!("{:?}", resp);
+    //Packets are up to 64 bytes long + first byte is Report ID,
+    let buffer = &mut [0_u8; 65];
+
+    for chunk in data.chunks(63) {
+        buffer[1] = (ptype as u8) << 6 | chunk.len() as u8;
+        buffer[2..(chunk.len() + 2)].copy_from_slice(chunk);
 
-    Ok(resp)
+        log::debug!("tx: {:02X?}", &buffer[..(chunk.len() + 2)]);
+        d.hf2_write(&buffer[..(chunk.len() + 2)])?;
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -263,7 +392,7 @@ mod tests {
         W: Fn(&[u8]) -> usize,
     {
         fn hf2_write(&self, data: &[u8]) -> Result<usize, Error> {
-            let len = (&self.writer)(data);
+            let len = (self.writer)(data);
 
             Ok(len)
         }
@@ -361,6 +490,32 @@ mod tests {
         xmit(command, &mock).unwrap();
     }
 
+    #[test]
+    fn send_serial() {
+        let data: Vec<Vec<u8>> = vec![vec![0x00, 0x83, b'h', b'i', b'\n']];
+
+        let writer = |v: &[u8]| -> usize {
+            static mut I: usize = 0;
+
+            let res: &Vec<u8> = unsafe {
+                let res = &data[I];
+                I += 1;
+                res
+            };
+
+            assert_eq!(res.as_slice(), v);
+
+            v.len()
+        };
+
+        let mock = MyMock {
+            reader: || vec![],
+            writer,
+        };
+
+        xmit_serial(Serial::StdOut, b"hi\n", &mock).unwrap();
+    }
+
     #[test]
     fn receive_fragmented() {
         let data: Vec<Vec<u8>> = vec![
@@ -410,7 +565,128 @@ mod tests {
             data: result.to_vec(),
         };
 
-        let rsp = rx(&mock).unwrap();
+        let rsp = rx(0x0004, &mock).unwrap();
+        assert_eq!(rsp, response);
+    }
+
+    #[test]
+    fn receive_discards_stale_tag() {
+        let data: Vec<Vec<u8>> = vec![
+            // late response to an earlier command with tag 3
+            vec![0x45, 0x03, 0x00, 0x00, 0x00, 0xAA],
+            vec![0x45, 0x04, 0x00, 0x00, 0x00, 0xBB],
+        ];
+
+        let reader = || -> Vec<u8> {
+            static mut I: usize = 0;
+
+            let res: &Vec<u8> = unsafe {
+                let res = &data[I];
+                I += 1;
+                res
+            };
+
+            res.to_vec()
+        };
+
+        let mock = MyMock {
+            reader,
+            writer: |_v| 0,
+        };
+
+        let response = CommandResponse {
+            tag: 0x0004,
+            status: CommandResponseStatus::Success,
+            status_info: 0x00,
+            data: vec![0xBB],
+        };
+
+        let rsp = rx(0x0004, &mock).unwrap();
+        assert_eq!(rsp, response);
+    }
+
+    #[test]
+    fn receive_interleaved_serial() {
+        use std::cell::RefCell;
+
+        struct Chatty {
+            reads: RefCell<Vec<Vec<u8>>>,
+            serial: RefCell<Vec<(Serial, Vec<u8>)>>,
+        }
+
+        impl ReadWrite for Chatty {
+            fn hf2_write(&self, data: &[u8]) -> Result<usize, Error> {
+                Ok(data.len())
+            }
+            fn hf2_read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+                let data = self.reads.borrow_mut().remove(0);
+                buf[..data.len()].copy_from_slice(&data);
+                Ok(data.len())
+            }
+            fn hf2_serial(&self, stream: Serial, data: &[u8]) {
+                self.serial.borrow_mut().push((stream, data.to_vec()));
+            }
+        }
+
+        let chatty = Chatty {
+            reads: RefCell::new(vec![
+                vec![0x82, b'h', b'i'],
+                vec![0x05, 0x04, 0x00, 0x00, 0x00, 0xAA],
+                vec![0xC3, b'e', b'r', b'r'],
+                vec![0x41, 0xBB],
+            ]),
+            serial: RefCell::new(vec![]),
+        };
+
+        let response = CommandResponse {
+            tag: 0x0004,
+            status: CommandResponseStatus::Success,
+            status_info: 0x00,
+            data: vec![0xAA, 0xBB],
+        };
+
+        let rsp = rx(0x0004, &chatty).unwrap();
         assert_eq!(rsp, response);
+        assert_eq!(
+            *chatty.serial.borrow(),
+            vec![
+                (Serial::StdOut, b"hi".to_vec()),
+                (Serial::StdErr, b"err".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn xmit_drains_pending() {
+        use std::cell::RefCell;
+
+        struct Pending(RefCell<Vec<Vec<u8>>>);
+
+        impl ReadWrite for Pending {
+            fn hf2_write(&self, data: &[u8]) -> Result<usize, Error> {
+                assert!(self.0.borrow().is_empty());
+                Ok(data.len())
+            }
+            fn hf2_read(&self, _buf: &mut [u8]) -> Result<usize, Error> {
+                Ok(0)
+            }
+            fn hf2_read_nonblocking(&self, buf: &mut [u8]) -> Result<usize, Error> {
+                match self.0.borrow_mut().pop() {
+                    Some(data) => {
+                        buf[..data.len()].copy_from_slice(&data);
+                        Ok(data.len())
+                    }
+                    None => Ok(0),
+                }
+            }
+        }
+
+        let pending = Pending(RefCell::new(vec![
+            vec![0x45, 0x03, 0x00, 0x00, 0x00, 0xAA],
+            vec![0x05, 0x03, 0x00, 0x00, 0x00, 0xAA],
+        ]));
+
+        xmit(Command::new(0x0001, 4, vec![]), &pending).unwrap();
+        assert!(pending.0.borrow().is_empty());
     }
 }
@@ -1,21 +1,12 @@
-use crate::command::{rx, xmit, Command, CommandResponse, CommandResponseStatus};
-use crate::Error;
+use crate::command::{send, Command};
+use crate::{Error, ReadWrite};
 use scroll::{ctx, Pread, LE};
 
 ///Return internal log buffer if any. The result is a character array.
-
-pub fn dmesg(d: &hidapi::HidDevice) -> Result<DmesgResponse, Error> {
-    xmit(Command::new(0x0010, 0, vec![]), d)?;
-
-    match rx(d) {
-        Ok(CommandResponse {
-            status: CommandResponseStatus::Success,
-            data,
-            ..
-        }) => (data.as_slice()).pread_with(0, LE),
-        Ok(_) => Err(Error::CommandNotRecognized),
-        Err(e) => Err(e),
-    }
+pub fn dmesg(d: &impl ReadWrite) -> Result<DmesgResponse, Error> {
+    send(Command::new(0x0010, d.hf2_next_tag(), vec![]), d)
+        .and_then(|data| data.as_slice().pread_with(0, LE))
+        .map_err(|e| e.context(0x0010, None))
 }
 
 ///Response to the dmesg command
@@ -0,0 +1,507 @@
+use crate::{BinInfoMode, BinInfoResponse, Error, FamilyId, ReadWrite, Serial};
+use core::cell::{Cell, RefCell};
+use crc_any::CRCu16;
+use scroll::{Pread, Pwrite, LE};
+use std::collections::{BTreeMap, VecDeque};
+
+const SUCCESS: u8 = 0x00;
+const PARSE_ERROR: u8 = 0x01;
+const EXECUTION_ERROR: u8 = 0x02;
+
+///Simulated HF2 bootloader with an in memory flash, for testing without a board. Flash starts at address 0 and reads erased as 0xFF, any other address is plain word addressable memory reading as 0.
+pub struct Emulator {
+    flash_page_size: u32,
+    flash_num_pages: u32,
+    max_message_size: u32,
+    family_id: FamilyId,
+    tag: Cell<u16>,
+    state: RefCell<State>,
+}
+
+struct State {
+    mode: BinInfoMode,
+    flash: Vec<u8>,
+    memory: BTreeMap<u32, u32>,
+    info: String,
+    dmesg: String,
+    ///command packets received so far
+    command: Vec<u8>,
+    ///reports waiting to be read by the host
+    reports: VecDeque<Vec<u8>>,
+    ///serial data sent by the host
+    input: Vec<u8>,
+}
+
+impl Emulator {
+    ///Panics unless the page size is a non zero power of two, the flash fits in the 32 bit address space and a message has room for more than the 8 byte command header.
+    pub fn new(
+        flash_page_size: u32,
+        flash_num_pages: u32,
+        max_message_size: u32,
+        family_id: FamilyId,
+    ) -> Self {
+        assert!(
+            flash_page_size.is_power_of_two(),
+            "flash page size must be a non zero power of two"
+        );
+        assert!(
+            flash_page_size.checked_mul(flash_num_pages).is_some(),
+            "flash must fit in the 32 bit address space"
+        );
+        assert!(
+            max_message_size > 8,
+            "max message size must leave room past the command header"
+        );
+
+        Self {
+            flash_page_size,
+            flash_num_pages,
+            max_message_size,
+            family_id,
+            tag: Cell::new(0),
+            state: RefCell::new(State {
+                mode: BinInfoMode::Bootloader,
+                flash: vec![0xFF; (flash_page_size * flash_num_pages) as usize],
+                memory: BTreeMap::new(),
+                info: "UF2 Bootloader v3.6.0 HF2 emulator\r\nModel: Emulator\r\nBoard-ID: Emulator\r\n"
+                    .into(),
+                dmesg: String::new(),
+                command: vec![],
+                reports: VecDeque::new(),
+                input: vec![],
+            }),
+        }
+    }
+
+    ///What BININFO answers, without going through the transport
+    pub fn bininfo(&self) -> BinInfoResponse {
+        BinInfoResponse {
+            mode: self.mode(),
+            flash_page_size: self.flash_page_size,
+            flash_num_pages: self.flash_num_pages,
+            max_message_size: self.max_message_size,
+            family_id: Some(self.family_id),
+        }
+    }
+
+    ///Current mode, bootloader until reset into app
+    pub fn mode(&self) -> BinInfoMode {
+        self.state.borrow().mode
+    }
+
+    pub fn set_mode(&self, mode: BinInfoMode) {
+        self.state.borrow_mut().mode = mode;
+    }
+
+    ///Copy of the whole flash
+    pub fn flash(&self) -> Vec<u8> {
+        self.state.borrow().flash.clone()
+    }
+
+    ///Overwrite flash starting at address, as if it had been programmed earlier
+    pub fn set_flash(&self, address: u32, data: &[u8]) {
+        let flash = &mut self.state.borrow_mut().flash;
+        flash[address as usize..][..data.len()].copy_from_slice(data);
+    }
+
+    ///Word at address, from flash or memory
+    pub fn word(&self, address: u32) -> u32 {
+        self.state.borrow().read_word(address)
+    }
+
+    ///Set a word of memory outside flash, like a register or ram the app would have written
+    pub fn set_word(&self, address: u32, value: u32) {
+        self.state.borrow_mut().memory.insert(address, value);
+    }
+
+    pub fn set_info(&self, info: &str) {
+        self.state.borrow_mut().info = info.into();
+    }
+
+    pub fn set_dmesg(&self, dmesg: &str) {
+        self.state.borrow_mut().dmesg = dmesg.into();
+    }
+
+    ///Queue serial output, as if printed by the app
+    pub fn print(&self, stream: Serial, data: &[u8]) {
+        let ptype = match stream {
+            Serial::StdOut => 2,
+            Serial::StdErr => 3,
+        };
+        let reports = &mut self.state.borrow_mut().reports;
+        for chunk in data.chunks(63) {
+            reports.push_back(report(ptype, chunk));
+        }
+    }
+
+    ///Serial input sent by the host so far
+    pub fn input(&self) -> Vec<u8> {
+        self.state.borrow().input.clone()
+    }
+
+    fn flash_size(&self) -> u32 {
+        self.flash_page_size * self.flash_num_pages
+    }
+
+    ///Execute a reassembled command, returning status, status_info and data, or None if the command never responds
+    fn execute(&self, id: u32, data: &[u8]) -> Option<(u8, Vec<u8>)> {
+        let mut state = self.state.borrow_mut();
+
+        let result = match id {
+            // BININFO
+            0x0001 => {
+                let mut buffer = vec![0_u8; 20];
+                let mut offset = 0;
+                buffer
+                    .gwrite_with(state.mode as u32, &mut offset, LE)
+                    .ok()?;
+                buffer
+                    .gwrite_with(self.flash_page_size, &mut offset, LE)
+                    .ok()?;
+                buffer
+                    .gwrite_with(self.flash_num_pages, &mut offset, LE)
+                    .ok()?;
+                buffer
+                    .gwrite_with(self.max_message_size, &mut offset, LE)
+                    .ok()?;
+                buffer
+                    .gwrite_with(u32::from(self.family_id), &mut offset, LE)
+                    .ok()?;
+                Ok(buffer)
+            }
+            // INFO
+            0x0002 => Ok(state.info.as_bytes().to_vec()),
+            // RESET INTO APP
+            0x0003 => {
+                state.mode = BinInfoMode::User;
+                return None;
+            }
+            // RESET INTO BOOTLOADER
+            0x0004 => {
+                state.mode = BinInfoMode::Bootloader;
+                return None;
+            }
+            // START FLASH
+            0x0005 => {
+                state.mode = BinInfoMode::Bootloader;
+                Ok(vec![])
+            }
+            // WRITE FLASH PAGE
+            0x0006 => data
+                .pread_with::<u32>(0, LE)
+                .map_err(|_| PARSE_ERROR)
+                .and_then(|address| {
+                    let page = &data[4..];
+                    if state.mode != BinInfoMode::Bootloader
+                        || address % self.flash_page_size != 0
+                        || page.len() > self.flash_page_size as usize
+                        || address as u64 + self.flash_page_size as u64 > self.flash_size() as u64
+                    {
+                        return Err(EXECUTION_ERROR);
+                    }
+                    state.flash[address as usize..][..page.len()].copy_from_slice(page);
+                    Ok(vec![])
+                }),
+            // CHKSUM PAGES
+            0x0007 => read_two(data).and_then(|(address, num_pages)| {
+                if num_pages > (self.max_message_size / 2).saturating_sub(2)
+                    || address % self.flash_page_size != 0
+                    || address as u64 + num_pages as u64 * self.flash_page_size as u64
+                        > self.flash_size() as u64
+                {
+                    return Err(EXECUTION_ERROR);
+                }
+                let mut buffer = vec![];
+                for page in state.flash[address as usize..]
+                    .chunks(self.flash_page_size as usize)
+                    .take(num_pages as usize)
+                {
+                    let mut xmodem = CRCu16::crc16xmodem();
+                    xmodem.digest(page);
+                    buffer.extend_from_slice(&xmodem.get_crc().to_le_bytes());
+                }
+                Ok(buffer)
+            }),
+            // READ WORDS
+            0x0008 => read_two(data).and_then(|(address, num_words)| {
+                if address % 4 != 0
+                    || num_words as u64 * 4 > self.max_message_size.saturating_sub(4) as u64
+                    || address as u64 + num_words as u64 * 4 > 1 << 32
+                {
+                    return Err(EXECUTION_ERROR);
+                }
+                let mut buffer = vec![];
+                for i in 0..num_words {
+                    buffer.extend_from_slice(&state.read_word(address + i * 4).to_le_bytes());
+                }
+                Ok(buffer)
+            }),
+            // WRITE WORDS
+            0x0009 => read_two(data).and_then(|(address, num_words)| {
+                if address % 4 != 0
+                    || address < self.flash_size()
+                    || (data.len() as u64) < 8 + num_words as u64 * 4
+                    || address as u64 + num_words as u64 * 4 > 1 << 32
+                {
+                    return Err(EXECUTION_ERROR);
+                }
+                for i in 0..num_words {
+                    let word = data
+                        .pread_with::<u32>(8 + i as usize * 4, LE)
+                        .map_err(|_| PARSE_ERROR)?;
+                    state.memory.insert(address + i * 4, word);
+                }
+                Ok(vec![])
+            }),
+            // DMESG
+            0x0010 => Ok(state.dmesg.as_bytes().to_vec()),
+            _ => Err(PARSE_ERROR),
+        };
+
+        Some(match result {
+            Ok(data) => (SUCCESS, data),
+            Err(status) => (status, vec![]),
+        })
+    }
+
+    ///Handle a complete command message, queueing the fragmented response
+    fn receive(&self, message: &[u8]) {
+        log::debug!("emulator rx: {:02X?}", message);
+
+        if message.len() < 8 {
+            return;
+        }
+        let id = message.pread_with::<u32>(0, LE).unwrap_or_default();
+        let tag = message.pread_with::<u16>(4, LE).unwrap_or_default();
+
+        let (status, data) = if message.len() > self.max_message_size as usize {
+            (PARSE_ERROR, vec![])
+        } else {
+            match self.execute(id, &message[8..]) {
+                Some(response) => response,
+                None => return,
+            }
+        };
+
+        let mut response = tag.to_le_bytes().to_vec();
+        response.extend_from_slice(&[status, 0]);
+        response.extend_from_slice(&data);
+
+        let reports = &mut self.state.borrow_mut().reports;
+        let mut chunks = response.chunks(63).peekable();
+        while let Some(chunk) = chunks.next() {
+            let ptype = if chunks.peek().is_some() { 0 } else { 1 };
+            reports.push_back(report(ptype, chunk));
+        }
+    }
+}
+
+impl Default for Emulator {
+    ///Something like a 512kb ATSAMD51
+    fn default() -> Self {
+        Emulator::new(512, 1024, 1024, FamilyId::ATSAMD51)
+    }
+}
+
+impl State {
+    fn read_word(&self, address: u32) -> u32 {
+        match self.flash.get(address as usize..address as usize + 4) {
+            Some(bytes) => bytes.pread_with::<u32>(0, LE).unwrap_or_default(),
+            None => self.memory.get(&address).copied().unwrap_or_default(),
+        }
+    }
+}
+
+///Read the two u32 arguments most commands start with
+fn read_two(data: &[u8]) -> Result<(u32, u32), u8> {
+    let first = data.pread_with::<u32>(0, LE).map_err(|_| PARSE_ERROR)?;
+    let second = data.pread_with::<u32>(4, LE).map_err(|_| PARSE_ERROR)?;
+    Ok((first, second))
+}
+
+///A full 64 byte report with header
+fn report(ptype: u8, data: &[u8]) -> Vec<u8> {
+    let mut report = vec![0_u8; 64];
+    report[0] = ptype << 6 | data.len() as u8;
+    report[1..(data.len() + 1)].copy_from_slice(data);
+    report
+}
+
+impl ReadWrite for Emulator {
+    fn hf2_write(&self, data: &[u8]) -> Result<usize, Error> {
+        // skip report id
+        if data.len() < 2 {
+            return Err(Error::Arguments);
+        }
+        let ptype = data[1] >> 6;
+        let len = (data[1] & 0x3F) as usize;
+        let packet = data.get(2..(len + 2)).ok_or(Error::Arguments)?;
+
+        let message = {
+            let mut state = self.state.borrow_mut();
+            match ptype {
+                0 => {
+                    state.command.extend_from_slice(packet);
+                    None
+                }
+                1 => {
+                    state.command.extend_from_slice(packet);
+                    Some(core::mem::take(&mut state.command))
+                }
+                _ => {
+                    state.input.extend_from_slice(packet);
+                    None
+                }
+            }
+        };
+
+        if let Some(message) = message {
+            self.receive(&message);
+        }
+
+        Ok(data.len())
+    }
+
+    ///Returns 0 like a timed out read when there's nothing to say
+    fn hf2_read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        match self.state.borrow_mut().reports.pop_front() {
+            Some(report) => {
+                let count = report.len().min(buf.len());
+                buf[..count].copy_from_slice(&report[..count]);
+                Ok(count)
+            }
+            None => Ok(0),
+        }
+    }
+
+    fn hf2_read_nonblocking(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.hf2_read(buf)
+    }
+
+    fn hf2_next_tag(&self) -> u16 {
+        let tag = self.tag.get();
+        self.tag.set(tag.wrapping_add(1));
+        tag
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bin_info() {
+        let emulator = Emulator::default();
+
+        let bininfo = crate::bin_info(&emulator).unwrap();
+        assert_eq!(bininfo.mode, BinInfoMode::Bootloader);
+        assert_eq!(bininfo.flash_page_size, 512);
+        assert_eq!(bininfo.flash_num_pages, 1024);
+        assert_eq!(bininfo.max_message_size, 1024);
+        assert_eq!(bininfo.family_id, Some(FamilyId::ATSAMD51));
+    }
+
+    #[test]
+    fn write_and_checksum_page() {
+        let emulator = Emulator::default();
+
+        let page: Vec<u8> = (0..512).map(|i| i as u8).collect();
+        crate::write_flash_page(&emulator, 0x4000, page.clone()).unwrap();
+        assert_eq!(&emulator.flash()[0x4000..0x4200], page.as_slice());
+
+        let mut xmodem = CRCu16::crc16xmodem();
+        xmodem.digest(&page);
+
+        let chk = crate::checksum_pages(&emulator, 0x4000, 2).unwrap();
+        assert_eq!(chk.checksums.len(), 2);
+        assert_eq!(chk.checksums[0], xmodem.get_crc());
+    }
+
+    #[test]
+    fn write_flash_page_needs_bootloader() {
+        let emulator = Emulator::default();
+        crate::reset_into_app(&emulator).unwrap();
+        assert_eq!(emulator.mode(), BinInfoMode::User);
+
+        assert!(matches!(
+            crate::write_flash_page(&emulator, 0x4000, vec![0; 512])
+                .unwrap_err()
+                .root(),
+            Error::Execution { .. }
+        ));
+        assert_eq!(emulator.word(0x4000), 0xFFFF_FFFF);
+
+        crate::start_flash(&emulator).unwrap();
+        crate::write_flash_page(&emulator, 0x4000, vec![0; 512]).unwrap();
+        assert_eq!(emulator.word(0x4000), 0);
+    }
+
+    #[test]
+    fn read_and_write_words() {
+        let emulator = Emulator::default();
+        emulator.set_flash(0x4000, &[0x01, 0x02, 0x03, 0x04]);
+
+        let flash = crate::read_words(&emulator, 0x4000, 2).unwrap();
+        assert_eq!(flash.words, vec![0x0403_0201, 0xFFFF_FFFF]);
+
+        crate::write_words(&emulator, 0x2000_0000, 2, vec![0xDEAD_BEEF, 42]).unwrap();
+        let ram = crate::read_words(&emulator, 0x2000_0000, 2).unwrap();
+        assert_eq!(ram.words, vec![0xDEAD_BEEF, 42]);
+    }
+
+    fn execution<T: core::fmt::Debug>(result: Result<T, Error>) -> bool {
+        matches!(result.unwrap_err().root(), Error::Execution { .. })
+    }
+
+    #[test]
+    fn invalid_geometry() {
+        let panics = |page_size, num_pages, max_message_size| {
+            std::panic::catch_unwind(|| {
+                Emulator::new(page_size, num_pages, max_message_size, FamilyId::ATSAMD21)
+            })
+            .is_err()
+        };
+        assert!(panics(0, 16, 64));
+        assert!(panics(300, 16, 64));
+        assert!(panics(0x1000, 0x10_0000, 64));
+        assert!(panics(512, 16, 8));
+        assert!(!panics(512, 16, 9));
+    }
+
+    #[test]
+    fn out_of_range_arguments() {
+        let emulator = Emulator::default();
+        assert!(execution(crate::checksum_pages(&emulator, 0xFFFF_FE00, 2)));
+        assert!(execution(crate::write_flash_page(
+            &emulator,
+            0xFFFF_FE00,
+            vec![0; 512]
+        )));
+        assert!(execution(crate::read_words(&emulator, 0xFFFF_FFFC, 2)));
+        assert!(execution(crate::write_words(
+            &emulator,
+            0xFFFF_FFFC,
+            2,
+            vec![0, 0]
+        )));
+
+        // the smallest message a command fits in, room for 3 words or 6 checksums
+        let emulator = Emulator::new(512, 16, 16, FamilyId::ATSAMD21);
+        assert!(execution(crate::checksum_pages(&emulator, 0, 7)));
+        assert!(execution(crate::read_words(&emulator, 0, 4)));
+        assert_eq!(crate::read_words(&emulator, 0, 3).unwrap().words.len(), 3);
+    }
+
+    #[test]
+    fn strings_and_serial() {
+        let emulator = Emulator::default();
+        emulator.set_dmesg("booted\n");
+        emulator.print(Serial::StdErr, b"oops");
+
+        assert_eq!(crate::dmesg(&emulator).unwrap().logs, "booted\n");
+        assert!(crate::info(&emulator).unwrap().info.contains("Emulator"));
+
+        crate::write_serial(&emulator, b"hello\n").unwrap();
+        assert_eq!(emulator.input(), b"hello\n");
+    }
+}
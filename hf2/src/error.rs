@@ -0,0 +1,160 @@
+use core::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    ///arguments couldn't be encoded into a command
+    Arguments,
+    ///response couldn't be decoded
+    Parse,
+    ///no response arrived before the transport timed out
+    Timeout,
+    ///only responses to other commands arrived
+    Sequence {
+        expected: u16,
+        received: u16,
+    },
+    ///device answered with status ParseError, it didn't understand the command
+    CommandNotRecognized {
+        status_info: u8,
+    },
+    ///device answered with status ExecutionError
+    Execution {
+        status_info: u8,
+    },
+    ///transport failed, for example with a hidapi::HidError
+    Transmission(Box<dyn std::error::Error + Send + Sync>),
+    Io(std::io::Error),
+    ///any other error, while running the command with this id at target_address if it has one
+    Command {
+        id: u32,
+        target_address: Option<u32>,
+        source: Box<Error>,
+    },
+}
+
+impl Error {
+    ///Wrap with the command that failed
+    pub(crate) fn context(self, id: u32, target_address: Option<u32>) -> Self {
+        Error::Command {
+            id,
+            target_address,
+            source: Box::new(self),
+        }
+    }
+
+    ///Innermost error, skipping any command context
+    pub fn root(&self) -> &Error {
+        match self {
+            Error::Command { source, .. } => source.root(),
+            _ => self,
+        }
+    }
+}
+
+///Name of a command from the HF2 spec
+pub fn command_name(id: u32) -> &'static str {
+    match id {
+        0x0001 => "BININFO",
+        0x0002 => "INFO",
+        0x0003 => "RESET INTO APP",
+        0x0004 => "RESET INTO BOOTLOADER",
+        0x0005 => "START FLASH",
+        0x0006 => "WRITE FLASH PAGE",
+        0x0007 => "CHKSUM PAGES",
+        0x0008 => "READ WORDS",
+        0x0009 => "WRITE WORDS",
+        0x0010 => "DMESG",
+        _ => "unknown command",
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Arguments => write!(f, "couldn't encode command arguments"),
+            Error::Parse => write!(f, "couldn't parse response"),
+            Error::Timeout => write!(f, "timed out waiting for a response"),
+            Error::Sequence { expected, received } => write!(
+                f,
+                "expected response tag {:#06X} but got {:#06X}",
+                expected, received
+            ),
+            Error::CommandNotRecognized { status_info } => write!(
+                f,
+                "device didn't recognize the command (status info {:#04X})",
+                status_info
+            ),
+            Error::Execution { status_info } => write!(
+                f,
+                "device failed executing the command (status info {:#04X})",
+                status_info
+            ),
+            Error::Transmission(_) => write!(f, "transmission failed"),
+            Error::Io(_) => write!(f, "io failed"),
+            Error::Command {
+                id,
+                target_address: Some(address),
+                ..
+            } => write!(
+                f,
+                "{} ({:#06X}) at {:#010X} failed",
+                command_name(*id),
+                id,
+                address
+            ),
+            Error::Command { id, .. } => {
+                write!(f, "{} ({:#06X}) failed", command_name(*id), id)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Transmission(e) => Some(e.as_ref()),
+            Error::Io(e) => Some(e),
+            Error::Command { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<scroll::Error> for Error {
+    fn from(_err: scroll::Error) -> Self {
+        Error::Parse
+    }
+}
+
+impl From<core::str::Utf8Error> for Error {
+    fn from(_err: core::str::Utf8Error) -> Self {
+        Error::Parse
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn display_with_context() {
+        let err = Error::Execution { status_info: 0x01 }.context(0x0006, Some(0x4000));
+
+        assert_eq!(
+            err.to_string(),
+            "WRITE FLASH PAGE (0x0006) at 0x00004000 failed"
+        );
+        assert_eq!(
+            err.source().unwrap().to_string(),
+            "device failed executing the command (status info 0x01)"
+        );
+        assert!(matches!(err.root(), Error::Execution { status_info: 0x01 }));
+    }
+}
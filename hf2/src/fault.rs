@@ -0,0 +1,277 @@
+use crate::{Error, ReadWrite, Serial};
+use core::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+
+///Chance of each fault, out of 1000, applied to every report read from the device. Writes to the device are passed through untouched.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct Faults {
+    ///report is lost
+    pub drop: u16,
+    ///report arrives twice
+    pub duplicate: u16,
+    ///report arrives shorter than its header says
+    pub truncate: u16,
+    ///length bits in the header byte are garbage
+    pub corrupt_length: u16,
+    ///the rest of the response only arrives after the host gave up and sent its next command
+    pub delay: u16,
+    ///response status is replaced with ExecutionError
+    pub execution_error: u16,
+}
+
+///Wraps any transport, injecting faults deterministically from a seed so failures can be reproduced
+pub struct Faulty<T: ReadWrite> {
+    inner: T,
+    faults: Faults,
+    rng: Cell<u64>,
+    ///reports to deliver before reading the inner transport again
+    pending: RefCell<VecDeque<Vec<u8>>>,
+    ///reports held back by a delay until the next write
+    delayed: RefCell<Vec<Vec<u8>>>,
+    ///next command report starts a response
+    first: Cell<bool>,
+    injected: Cell<usize>,
+}
+
+impl<T: ReadWrite> Faulty<T> {
+    pub fn new(inner: T, seed: u64, faults: Faults) -> Self {
+        Self {
+            inner,
+            faults,
+            // xorshift gets stuck on 0
+            rng: Cell::new(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1),
+            pending: RefCell::new(VecDeque::new()),
+            delayed: RefCell::new(vec![]),
+            first: Cell::new(true),
+            injected: Cell::new(0),
+        }
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    ///Number of faults injected so far
+    pub fn injected(&self) -> usize {
+        self.injected.get()
+    }
+
+    ///xorshift64*
+    fn random(&self) -> u64 {
+        let mut x = self.rng.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.rng.set(x);
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 32
+    }
+
+    fn roll(&self, chance: u16) -> bool {
+        let hit = self.random() % 1000 < u64::from(chance);
+        if hit {
+            self.injected.set(self.injected.get() + 1);
+        }
+        hit
+    }
+
+    fn read_report(&self) -> Result<Vec<u8>, Error> {
+        if let Some(report) = self.pending.borrow_mut().pop_front() {
+            return Ok(report);
+        }
+        let mut buf = [0_u8; 64];
+        let count = self.inner.hf2_read(&mut buf)?;
+        Ok(buf[..count].to_vec())
+    }
+}
+
+impl<T: ReadWrite> ReadWrite for Faulty<T> {
+    fn hf2_write(&self, data: &[u8]) -> Result<usize, Error> {
+        // whatever was delayed finally arrives, ahead of the response to this write
+        let mut pending = self.pending.borrow_mut();
+        for report in self.delayed.borrow_mut().drain(..).rev() {
+            pending.push_front(report);
+        }
+        drop(pending);
+
+        self.inner.hf2_write(data)
+    }
+
+    fn hf2_read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        loop {
+            let mut report = self.read_report()?;
+
+            // a delay holds back everything until the next write, reads time out meanwhile
+            if !self.delayed.borrow().is_empty()
+                || (!report.is_empty() && self.roll(self.faults.delay))
+            {
+                if !report.is_empty() {
+                    self.delayed.borrow_mut().push(report);
+                }
+                return Ok(0);
+            }
+
+            if report.is_empty() {
+                return Ok(0);
+            }
+
+            if self.roll(self.faults.drop) {
+                log::debug!("fault: dropped {:02X?}", report);
+                continue;
+            }
+
+            if self.roll(self.faults.duplicate) {
+                log::debug!("fault: duplicated {:02X?}", report);
+                self.pending.borrow_mut().push_front(report.clone());
+            }
+
+            let ptype = report[0] >> 6;
+            if ptype < 2 {
+                // status byte follows the header byte and 2 tag bytes
+                if self.first.get() && report.len() > 3 && self.roll(self.faults.execution_error) {
+                    log::debug!("fault: execution error {:02X?}", report);
+                    report[3] = 0x02;
+                }
+                self.first.set(ptype == 1);
+            }
+
+            if self.roll(self.faults.corrupt_length) {
+                log::debug!("fault: corrupted length {:02X?}", report);
+                report[0] = (report[0] & 0xC0) | (self.random() as u8 & 0x3F);
+            }
+
+            if self.roll(self.faults.truncate) {
+                log::debug!("fault: truncated {:02X?}", report);
+                let len = 1 + self.random() as usize % report.len();
+                report.truncate(len);
+            }
+
+            let count = report.len().min(buf.len());
+            buf[..count].copy_from_slice(&report[..count]);
+            return Ok(count);
+        }
+    }
+
+    fn hf2_read_nonblocking(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        // like hf2_read, nothing overtakes a delayed report before the next write
+        if !self.delayed.borrow().is_empty() {
+            let report = match self.pending.borrow_mut().pop_front() {
+                Some(report) => report,
+                None => {
+                    let mut report = [0_u8; 64];
+                    let count = self.inner.hf2_read_nonblocking(&mut report)?;
+                    report[..count].to_vec()
+                }
+            };
+            if !report.is_empty() {
+                self.delayed.borrow_mut().push(report);
+            }
+            return Ok(0);
+        }
+
+        if let Some(report) = self.pending.borrow_mut().pop_front() {
+            let count = report.len().min(buf.len());
+            buf[..count].copy_from_slice(&report[..count]);
+            return Ok(count);
+        }
+        self.inner.hf2_read_nonblocking(buf)
+    }
+
+    fn hf2_serial(&self, stream: Serial, data: &[u8]) {
+        self.inner.hf2_serial(stream, data)
+    }
+
+    fn hf2_next_tag(&self) -> u16 {
+        self.inner.hf2_next_tag()
+    }
+}
+
+#[cfg(all(test, feature = "emulator", feature = "utils"))]
+mod tests {
+    use super::*;
+    use crate::emulator::Emulator;
+
+    fn faults() -> Faults {
+        Faults {
+            drop: 20,
+            duplicate: 20,
+            truncate: 20,
+            corrupt_length: 20,
+            delay: 20,
+            execution_error: 20,
+        }
+    }
+
+    #[test]
+    fn deterministic() {
+        let run = |seed| {
+            let faulty = Faulty::new(Emulator::default(), seed, faults());
+            let results: Vec<bool> = (0..200).map(|_| crate::bin_info(&faulty).is_ok()).collect();
+            (results, faulty.injected())
+        };
+
+        assert_eq!(run(7), run(7));
+        assert!(run(7).1 > 0);
+        assert!(run(7).0.contains(&false));
+    }
+
+    #[test]
+    fn flash_recovers() {
+        let (binary, address) = crate::utils::elf_to_bin(
+            [
+                env!("CARGO_MANIFEST_DIR"),
+                "src/utils/testdata/blinky_1.47.0",
+            ]
+            .iter()
+            .collect(),
+        )
+        .unwrap();
+
+        let mut injected = 0;
+        for seed in 0..50 {
+            let faulty = Faulty::new(Emulator::default(), seed, faults());
+            let bininfo = faulty.inner().bininfo();
+
+            crate::utils::flash_bin(
+                &binary,
+                address,
+                crate::utils::VerifyMode::Crc,
+                &bininfo,
+                &faulty,
+            )
+            .unwrap();
+            assert_eq!(
+                &faulty.inner().flash()[address as usize..][..binary.len()],
+                binary.as_slice(),
+                "seed {}",
+                seed
+            );
+            injected += faulty.injected();
+        }
+        assert!(injected > 10);
+    }
+
+    #[test]
+    fn nonblocking_reads_wait_for_delayed_reports() {
+        let faulty = Faulty::new(
+            Emulator::default(),
+            1,
+            Faults {
+                delay: 1000,
+                ..Faults::default()
+            },
+        );
+        let mut buf = [0_u8; 64];
+
+        faulty.inner().print(Serial::StdOut, b"first");
+        assert_eq!(faulty.hf2_read(&mut buf).unwrap(), 0);
+        faulty.inner().print(Serial::StdOut, b"second");
+        assert_eq!(faulty.hf2_read_nonblocking(&mut buf).unwrap(), 0);
+
+        // the next write releases both, in order
+        crate::write_serial(&faulty, b"x").unwrap();
+        faulty.hf2_read_nonblocking(&mut buf).unwrap();
+        assert_eq!(&buf[1..6], b"first");
+        faulty.hf2_read_nonblocking(&mut buf).unwrap();
+        assert_eq!(&buf[1..7], b"second");
+    }
+}
@@ -1,17 +1,59 @@
 use crate::{Error, ReadWrite};
+use core::ops::Deref;
+use core::sync::atomic::{AtomicU16, Ordering};
 use hidapi::HidDevice;
 
-impl ReadWrite for HidDevice {
+///A hidapi device along with its own command tag sequence, so commands to several devices in one process keep separate sequences.
+pub struct Device {
+    device: HidDevice,
+    tag: AtomicU16,
+}
+
+impl Device {
+    pub fn new(device: HidDevice) -> Self {
+        Self {
+            device,
+            tag: AtomicU16::new(0),
+        }
+    }
+
+    pub fn into_inner(self) -> HidDevice {
+        self.device
+    }
+}
+
+impl From<HidDevice> for Device {
+    fn from(device: HidDevice) -> Self {
+        Self::new(device)
+    }
+}
+
+///For the rest of hidapi, like the manufacturer and product strings.
+impl Deref for Device {
+    type Target = HidDevice;
+
+    fn deref(&self) -> &HidDevice {
+        &self.device
+    }
+}
+
+impl ReadWrite for Device {
     fn hf2_write(&self, data: &[u8]) -> Result<usize, Error> {
-        self.write(data).map_err(|e| e.into())
+        self.device.write(data).map_err(|e| e.into())
     }
     fn hf2_read(&self, buf: &mut [u8]) -> Result<usize, Error> {
-        self.read_timeout(buf, 1000).map_err(|e| e.into())
+        self.device.read_timeout(buf, 1000).map_err(|e| e.into())
+    }
+    fn hf2_read_nonblocking(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.device.read_timeout(buf, 0).map_err(|e| e.into())
+    }
+    fn hf2_next_tag(&self) -> u16 {
+        self.tag.fetch_add(1, Ordering::Relaxed)
     }
 }
 
 impl From<hidapi::HidError> for Error {
-    fn from(_err: hidapi::HidError) -> Self {
-        Error::Transmission
+    fn from(err: hidapi::HidError) -> Self {
+        Error::Transmission(Box::new(err))
     }
 }
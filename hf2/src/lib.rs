@@ -34,31 +34,59 @@ pub use startflash::*;
 mod writeflashpage;
 pub use writeflashpage::*;
 
+///Read and write any number of bytes at any address, split into aligned READ WORDS and WRITE WORDS that fit max_message_size.
+mod memory;
+pub use memory::*;
+
 ///Dual of READ WORDS, with the same constraints. No Result.
 mod writewords;
 pub use writewords::*;
 
+///Serial stdout and stderr the device may send at any time, in between command packets.
+mod serial;
+pub use serial::*;
+
 /// Errors and traits to build a command
 mod command;
 
-#[derive(Clone, Debug)]
-pub enum Error {
-    Arguments,
-    Parse,
-    CommandNotRecognized,
-    Execution,
-    Sequence,
-    Transmission,
-}
+///Errors, carrying the device status and which command and address failed
+mod error;
+pub use error::*;
 
-///trait to implement HID devices
+///trait to implement HID devices. All commands and utils are generic over it, Device wraps a hidapi::HidDevice to implement it behind the hidapi feature.
 pub trait ReadWrite {
     fn hf2_write(&self, data: &[u8]) -> Result<usize, Error>;
     fn hf2_read(&self, buf: &mut [u8]) -> Result<usize, Error>;
+
+    ///Read a report only if one is already pending, returning 0 otherwise. Used to drain stale input before sending a command. Defaults to never having anything pending.
+    fn hf2_read_nonblocking(&self, _buf: &mut [u8]) -> Result<usize, Error> {
+        Ok(0)
+    }
+
+    ///Called with serial stdout and stderr packets, which may arrive at any time, even in the middle of a command response. Defaults to logging them.
+    fn hf2_serial(&self, stream: Serial, data: &[u8]) {
+        log::info!("{:?}: {}", stream, String::from_utf8_lossy(data));
+    }
+
+    ///Tag for the next command, the device repeats it in its response. Defaults to a process wide counter shared by every transport that doesn't override it, Device and the emulator keep their own sequence per device.
+    fn hf2_next_tag(&self) -> u16 {
+        command::next_tag()
+    }
 }
 
+///hidapi devices wrapped with their own tag sequence
 #[cfg(feature = "hidapi")]
 mod hidapi_trait;
+#[cfg(feature = "hidapi")]
+pub use hidapi_trait::*;
 
 #[cfg(feature = "utils")]
 pub mod utils;
+
+///Simulated HF2 device for testing without a board
+#[cfg(feature = "emulator")]
+pub mod emulator;
+
+///Transport wrapper injecting faults, for testing recovery
+#[cfg(feature = "fault")]
+pub mod fault;
@@ -0,0 +1,164 @@
+use crate::{read_words, write_words, BinInfoResponse, Error, ReadWrite};
+
+///Read len bytes starting at any address. Split into as many aligned READ WORDS as max_message_size needs, bytes outside the range are trimmed off.
+pub fn read_memory(
+    d: &impl ReadWrite,
+    bininfo: &BinInfoResponse,
+    address: u32,
+    len: usize,
+) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![0_u8; len];
+    read_memory_into(d, bininfo, address, &mut buf)?;
+    Ok(buf)
+}
+
+///Like read_memory, filling buf.
+pub fn read_memory_into(
+    d: &impl ReadWrite,
+    bininfo: &BinInfoResponse,
+    address: u32,
+    buf: &mut [u8],
+) -> Result<(), Error> {
+    if buf.is_empty() {
+        return Ok(());
+    }
+    let (start, end) = aligned(address, buf.len())?;
+
+    // the response carries 4 bytes of header ahead of the words
+    let max_words = bininfo.max_message_size.saturating_sub(4) / 4;
+    if max_words == 0 {
+        return Err(Error::Arguments);
+    }
+
+    let mut data = Vec::with_capacity((end - start as u64) as usize);
+    // u64 so a read ending at the top of the address space stops there rather than wrapping to 0
+    let mut target_address = start as u64;
+    while target_address < end {
+        let num_words = ((end - target_address) / 4).min(max_words as u64) as u32;
+
+        let words = read_words(d, target_address as u32, num_words)?.words;
+        if words.len() < num_words as usize {
+            return Err(Error::Parse);
+        }
+        for word in &words[..num_words as usize] {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        target_address += num_words as u64 * 4;
+    }
+
+    buf.copy_from_slice(&data[(address - start) as usize..][..buf.len()]);
+    Ok(())
+}
+
+///Write data starting at any address. Split into as many aligned WRITE WORDS as max_message_size needs, the words holding unaligned start and end bytes are read first so their other bytes are kept.
+pub fn write_memory(
+    d: &impl ReadWrite,
+    bininfo: &BinInfoResponse,
+    address: u32,
+    data: &[u8],
+) -> Result<(), Error> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    let (start, end) = aligned(address, data.len())?;
+    let last_word = (end - 4) as u32;
+
+    let mut bytes = vec![0_u8; (end - start as u64) as usize];
+    if address != start {
+        read_memory_into(d, bininfo, start, &mut bytes[..4])?;
+    }
+    if address as u64 + data.len() as u64 != end {
+        let len = bytes.len();
+        read_memory_into(d, bininfo, last_word, &mut bytes[len - 4..])?;
+    }
+    bytes[(address - start) as usize..][..data.len()].copy_from_slice(data);
+
+    // the command carries 8 bytes of header, then address and count ahead of the words
+    let max_words = bininfo.max_message_size.saturating_sub(16) / 4;
+    if max_words == 0 {
+        return Err(Error::Arguments);
+    }
+
+    for (i, chunk) in bytes.chunks(max_words as usize * 4).enumerate() {
+        let words: Vec<u32> = chunk
+            .chunks(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect();
+        let target_address = start + i as u32 * max_words * 4;
+
+        write_words(d, target_address, words.len() as u32, words)?;
+    }
+    Ok(())
+}
+
+///Word aligned start and end around len bytes at address, end is u64 so a range may reach the top of the address space.
+fn aligned(address: u32, len: usize) -> Result<(u32, u64), Error> {
+    let end = address as u64 + len as u64;
+    if end > 1 << 32 {
+        return Err(Error::Arguments);
+    }
+    Ok((address & !3, (end + 3) & !3))
+}
+
+#[cfg(all(test, feature = "emulator"))]
+mod tests {
+    use super::*;
+    use crate::emulator::Emulator;
+    use crate::FamilyId;
+
+    #[test]
+    fn unaligned_read_and_write() {
+        // room for 4 words per READ WORDS and 1 per WRITE WORDS
+        let emulator = Emulator::new(512, 16, 20, FamilyId::ATSAMD21);
+        let bininfo = emulator.bininfo();
+        for i in 0..8 {
+            emulator.set_word(0x2000_0000 + i * 4, 0x1111_1111 * (i + 1));
+        }
+
+        let data = read_memory(&emulator, &bininfo, 0x2000_0003, 14).unwrap();
+        assert_eq!(
+            data,
+            vec![
+                0x11, 0x22, 0x22, 0x22, 0x22, 0x33, 0x33, 0x33, 0x33, 0x44, 0x44, 0x44, 0x44, 0x55
+            ]
+        );
+
+        write_memory(&emulator, &bininfo, 0x2000_0005, &[0xAA; 10]).unwrap();
+        assert_eq!(emulator.word(0x2000_0000), 0x1111_1111);
+        assert_eq!(emulator.word(0x2000_0004), 0xAAAA_AA22);
+        assert_eq!(emulator.word(0x2000_0008), 0xAAAA_AAAA);
+        assert_eq!(emulator.word(0x2000_000C), 0x44AA_AAAA);
+        assert_eq!(emulator.word(0x2000_0010), 0x5555_5555);
+
+        // within a single word
+        write_memory(&emulator, &bininfo, 0x2000_0011, &[0, 0]).unwrap();
+        assert_eq!(emulator.word(0x2000_0010), 0x5500_0055);
+
+        let mut buf = [0; 3];
+        read_memory_into(&emulator, &bininfo, 0x2000_0010, &mut buf).unwrap();
+        assert_eq!(buf, [0x55, 0, 0]);
+    }
+
+    #[test]
+    fn past_end_of_memory() {
+        let emulator = Emulator::default();
+        assert!(matches!(
+            read_memory(&emulator, &emulator.bininfo(), 0xFFFF_FFFE, 4),
+            Err(Error::Arguments)
+        ));
+    }
+
+    #[test]
+    fn top_of_memory() {
+        let emulator = Emulator::default();
+        emulator.set_word(0xFFFF_FFFC, 0x0403_0201);
+        assert_eq!(
+            read_memory(&emulator, &emulator.bininfo(), 0xFFFF_FFFC, 4).unwrap(),
+            vec![1, 2, 3, 4]
+        );
+        assert_eq!(
+            read_memory(&emulator, &emulator.bininfo(), 0xFFFF_FFFD, 3).unwrap(),
+            vec![2, 3, 4]
+        );
+    }
+}
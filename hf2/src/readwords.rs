@@ -1,10 +1,10 @@
-use crate::command::{rx, xmit, Command, CommandResponse, CommandResponseStatus};
-use crate::Error;
+use crate::command::{send, Command};
+use crate::{Error, ReadWrite};
 use scroll::{ctx, Pread, Pwrite, LE};
 
 ///Read a number of words from memory. Memory is read word by word (and not byte by byte), and target_addr must be suitably aligned. This is to support reading of special IO regions.
 pub fn read_words(
-    d: &hidapi::HidDevice,
+    d: &impl ReadWrite,
     target_address: u32,
     num_words: u32,
 ) -> Result<ReadWordsResponse, Error> {
@@ -14,17 +14,9 @@ pub fn read_words(
     buffer.gwrite_with(target_address, &mut offset, scroll::LE)?;
     buffer.gwrite_with(num_words, &mut offset, scroll::LE)?;
 
-    xmit(Command::new(0x0008, 0, buffer), d)?;
-
-    match rx(d) {
-        Ok(CommandResponse {
-            status: CommandResponseStatus::Success,
-            data,
-            ..
-        }) => (data.as_slice()).pread_with(0, LE),
-        Ok(_) => Err(Error::CommandNotRecognized),
-        Err(e) => Err(e),
-    }
+    send(Command::new(0x0008, d.hf2_next_tag(), buffer), d)
+        .and_then(|data| data.as_slice().pread_with(0, LE))
+        .map_err(|e| e.context(0x0008, Some(target_address)))
 }
 
 ///Response to the read_words command
@@ -1,7 +1,7 @@
 use crate::command::{xmit, Command};
-use crate::Error;
+use crate::{Error, ReadWrite};
 
 ///Reset the device into user-space app. Empty tuple response.
-pub fn reset_into_app(d: &hidapi::HidDevice) -> Result<(), Error> {
-    xmit(Command::new(0x0003, 0, vec![]), d)
+pub fn reset_into_app(d: &impl ReadWrite) -> Result<(), Error> {
+    xmit(Command::new(0x0003, d.hf2_next_tag(), vec![]), d).map_err(|e| e.context(0x0003, None))
 }
@@ -0,0 +1,26 @@
+use crate::command::{rx_serial, xmit_serial};
+use crate::{Error, ReadWrite};
+
+///Stream a serial packet from the device belongs to
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Serial {
+    StdOut,
+    StdErr,
+}
+
+///Wait for serial output from the device. None if nothing arrived before the transport timed out, or if a stray command packet arrived instead.
+pub fn read_serial(d: &impl ReadWrite) -> Result<Option<SerialResponse>, Error> {
+    rx_serial(d).map(|serial| serial.map(|(stream, data)| SerialResponse { stream, data }))
+}
+
+///Send serial input to the application running on the device. No Result.
+pub fn write_serial(d: &impl ReadWrite, data: &[u8]) -> Result<(), Error> {
+    xmit_serial(Serial::StdOut, data, d)
+}
+
+///Serial output read from the device
+#[derive(Debug, PartialEq)]
+pub struct SerialResponse {
+    pub stream: Serial,
+    pub data: Vec<u8>,
+}
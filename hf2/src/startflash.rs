@@ -1,9 +1,9 @@
-use crate::command::{rx, xmit, Command};
-use crate::Error;
+use crate::command::{send, Command};
+use crate::{Error, ReadWrite};
 
 /// When issued in bootloader mode, it has no effect. In user-space mode it causes handover to bootloader. A BININFO command can be issued to verify that. Empty tuple response.
-pub fn start_flash(d: &hidapi::HidDevice) -> Result<(), Error> {
-    xmit(Command::new(0x0005, 0, vec![]), d)?;
-
-    rx(d).map(|_| ())
+pub fn start_flash(d: &impl ReadWrite) -> Result<(), Error> {
+    send(Command::new(0x0005, d.hf2_next_tag(), vec![]), d)
+        .map(|_| ())
+        .map_err(|e| e.context(0x0005, None))
 }
@@ -0,0 +1,257 @@
+use super::UtilError;
+use crate::{read_words, BinInfoResponse, Error, FamilyId, ReadWrite};
+
+/// SAMD21 and SAMD51 DSU device identification register.
+const DSU_DID: u32 = 0x4100_2018;
+/// DID PROCESSOR, FAMILY and SERIES the DEVSEL tables below are for, SAME5x parts report the ATSAMD51 family id too.
+const SAMD21_SERIES: (u32, u32, u32) = (1, 0, 1);
+const SAMD51_SERIES: (u32, u32, u32) = (6, 0, 6);
+const SAMD21_SERIAL: [u32; 4] = [0x0080_A00C, 0x0080_A040, 0x0080_A044, 0x0080_A048];
+const SAMD51_SERIAL: [u32; 4] = [0x0080_61FC, 0x0080_6010, 0x0080_6014, 0x0080_6018];
+
+/// nRF52840 FICR registers.
+const FICR_DEVICEID: [u32; 2] = [0x1000_0060, 0x1000_0064];
+const FICR_INFO_PART: u32 = 0x1000_0100;
+const FICR_INFO_VARIANT: u32 = 0x1000_0104;
+
+/// STM32 DBGMCU_IDCODE, some STM32F1 revisions only let a debugger read it and return 0.
+const DBGMCU_IDCODE: u32 = 0xE004_2000;
+const STM32F103_UID: u32 = 0x1FFF_F7E8;
+const STM32F401_UID: u32 = 0x1FFF_7A10;
+
+/// Identity of the chip, decoded from its family's identification registers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChipId {
+    pub family: FamilyId,
+    /// Like SAMD51J19A or nRF52840, or the raw selector when it isn't known.
+    pub part: String,
+    pub revision: String,
+    /// Unique serial number in hex, 128 bits for SAMD, 64 for nRF and 96 for STM32.
+    pub serial: String,
+    /// The raw device id register, DSU DID, FICR INFO.PART or DBGMCU IDCODE.
+    pub device_id: u32,
+}
+
+/// Read and decode the chip's identification registers, picked by the family id BININFO reports.
+pub fn chip_id(bininfo: &BinInfoResponse, d: &impl ReadWrite) -> Result<ChipId, UtilError> {
+    let family = bininfo
+        .family_id
+        .ok_or(UtilError::UnsupportedFamily { family: None })?;
+
+    match family {
+        FamilyId::ATSAMD21 | FamilyId::ATSAMD51 => {
+            let did = word(d, DSU_DID)?;
+            let (series, parts, serial) = if family == FamilyId::ATSAMD21 {
+                (SAMD21_SERIES, SAMD21_PARTS, SAMD21_SERIAL)
+            } else {
+                (SAMD51_SERIES, SAMD51_PARTS, SAMD51_SERIAL)
+            };
+
+            let devsel = (did & 0xFF) as u8;
+            let did_series = (did >> 28, (did >> 23) & 0x1F, (did >> 16) & 0x3F);
+            // DEVSEL values are only unique within a series
+            let part = if did_series == series {
+                parts
+                    .iter()
+                    .find(|(selector, _)| *selector == devsel)
+                    .map_or(format!("DEVSEL 0x{:02X}", devsel), |(_, part)| {
+                        part.to_string()
+                    })
+            } else {
+                format!(
+                    "PROCESSOR {} FAMILY {} SERIES {} DEVSEL 0x{:02X}",
+                    did_series.0, did_series.1, did_series.2, devsel
+                )
+            };
+            let revision = ((b'A' + ((did >> 8) & 0xF) as u8) as char).to_string();
+
+            Ok(ChipId {
+                family,
+                part,
+                revision,
+                serial: serial_of(d, &serial)?,
+                device_id: did,
+            })
+        }
+        FamilyId::NRF52840 => {
+            let part = word(d, FICR_INFO_PART)?;
+            // four ascii characters, like AAD0
+            let variant = word(d, FICR_INFO_VARIANT)?.to_be_bytes();
+
+            Ok(ChipId {
+                family,
+                part: format!("nRF{:X}", part),
+                revision: String::from_utf8_lossy(&variant).into_owned(),
+                // DEVICEID[1] holds the upper half
+                serial: serial_of(d, &[FICR_DEVICEID[1], FICR_DEVICEID[0]])?,
+                device_id: part,
+            })
+        }
+        FamilyId::STM32F103 | FamilyId::STM32F401 => {
+            let idcode = word(d, DBGMCU_IDCODE)?;
+            let dev_id = idcode & 0xFFF;
+            let part = STM32_PARTS
+                .iter()
+                .find(|(id, _)| *id == dev_id)
+                .map_or(format!("DEV_ID 0x{:03X}", dev_id), |(_, part)| {
+                    part.to_string()
+                });
+
+            let uid = if family == FamilyId::STM32F103 {
+                STM32F103_UID
+            } else {
+                STM32F401_UID
+            };
+            Ok(ChipId {
+                family,
+                part,
+                revision: format!("0x{:04X}", idcode >> 16),
+                // the first word is the lowest bits
+                serial: serial_of(d, &[uid + 8, uid + 4, uid])?,
+                device_id: idcode,
+            })
+        }
+        family => Err(UtilError::UnsupportedFamily {
+            family: Some(family),
+        }),
+    }
+}
+
+/// SAMD21 DSU DID DEVSEL values.
+const SAMD21_PARTS: &[(u8, &str)] = &[
+    (0x00, "SAMD21J18A"),
+    (0x01, "SAMD21J17A"),
+    (0x02, "SAMD21J16A"),
+    (0x03, "SAMD21J15A"),
+    (0x05, "SAMD21G18A"),
+    (0x06, "SAMD21G17A"),
+    (0x07, "SAMD21G16A"),
+    (0x08, "SAMD21G15A"),
+    (0x0A, "SAMD21E18A"),
+    (0x0B, "SAMD21E17A"),
+    (0x0C, "SAMD21E16A"),
+    (0x0D, "SAMD21E15A"),
+];
+
+/// SAMD51 DSU DID DEVSEL values.
+const SAMD51_PARTS: &[(u8, &str)] = &[
+    (0x00, "SAMD51P20A"),
+    (0x01, "SAMD51P19A"),
+    (0x02, "SAMD51N20A"),
+    (0x03, "SAMD51N19A"),
+    (0x04, "SAMD51J20A"),
+    (0x05, "SAMD51J19A"),
+    (0x06, "SAMD51J18A"),
+    (0x07, "SAMD51G19A"),
+    (0x08, "SAMD51G18A"),
+];
+
+/// STM32 DBGMCU IDCODE DEV_ID values.
+const STM32_PARTS: &[(u32, &str)] = &[
+    (0x410, "STM32F10x medium density"),
+    (0x412, "STM32F10x low density"),
+    (0x414, "STM32F10x high density"),
+    (0x430, "STM32F10x XL density"),
+    (0x423, "STM32F401xB/C"),
+    (0x433, "STM32F401xD/E"),
+];
+
+fn word(d: &impl ReadWrite, address: u32) -> Result<u32, UtilError> {
+    read_words(d, address, 1)?
+        .words
+        .first()
+        .copied()
+        .ok_or(UtilError::Communication(Error::Parse))
+}
+
+/// Words read one by one, most significant first, as hex.
+fn serial_of(d: &impl ReadWrite, addresses: &[u32]) -> Result<String, UtilError> {
+    let mut serial = String::new();
+    for address in addresses {
+        serial.push_str(&format!("{:08X}", word(d, *address)?));
+    }
+    Ok(serial)
+}
+
+#[cfg(all(test, feature = "emulator"))]
+mod tests {
+    use super::*;
+    use crate::emulator::Emulator;
+
+    #[test]
+    fn samd51() {
+        let emulator = Emulator::default();
+        emulator.set_word(DSU_DID, 0x6006_0305);
+        for (i, address) in SAMD51_SERIAL.iter().enumerate() {
+            emulator.set_word(*address, 0x1111_1111 * (i as u32 + 1));
+        }
+
+        let id = chip_id(&emulator.bininfo(), &emulator).unwrap();
+        assert_eq!(id.family, FamilyId::ATSAMD51);
+        assert_eq!(id.part, "SAMD51J19A");
+        assert_eq!(id.revision, "D");
+        assert_eq!(id.serial, "11111111222222223333333344444444");
+        assert_eq!(id.device_id, 0x6006_0305);
+    }
+
+    #[test]
+    fn samd21_unknown_devsel() {
+        let emulator = Emulator::new(256, 64, 1024, FamilyId::ATSAMD21);
+        emulator.set_word(DSU_DID, 0x1001_0142);
+        emulator.set_word(SAMD21_SERIAL[3], 0xAB);
+
+        let id = chip_id(&emulator.bininfo(), &emulator).unwrap();
+        assert_eq!(id.part, "DEVSEL 0x42");
+        assert_eq!(id.revision, "B");
+        assert_eq!(id.serial, "000000000000000000000000000000AB");
+    }
+
+    #[test]
+    fn same54_isnt_a_samd51() {
+        let emulator = Emulator::default();
+        // SAME54P20A, whose DEVSEL is also SAMD51P20A's
+        emulator.set_word(DSU_DID, 0x6184_0300);
+
+        let id = chip_id(&emulator.bininfo(), &emulator).unwrap();
+        assert_eq!(id.part, "PROCESSOR 6 FAMILY 3 SERIES 4 DEVSEL 0x00");
+        assert_eq!(id.revision, "D");
+    }
+
+    #[test]
+    fn nrf52840() {
+        let emulator = Emulator::new(4096, 256, 1024, FamilyId::NRF52840);
+        emulator.set_word(FICR_INFO_PART, 0x52840);
+        emulator.set_word(FICR_INFO_VARIANT, 0x4141_4430);
+        emulator.set_word(FICR_DEVICEID[0], 0x89AB_CDEF);
+        emulator.set_word(FICR_DEVICEID[1], 0x0123_4567);
+
+        let id = chip_id(&emulator.bininfo(), &emulator).unwrap();
+        assert_eq!(id.part, "nRF52840");
+        assert_eq!(id.revision, "AAD0");
+        assert_eq!(id.serial, "0123456789ABCDEF");
+    }
+
+    #[test]
+    fn stm32f401() {
+        let emulator = Emulator::new(512, 64, 1024, FamilyId::STM32F401);
+        emulator.set_word(DBGMCU_IDCODE, 0x1000_6423);
+        emulator.set_word(STM32F401_UID, 1);
+        emulator.set_word(STM32F401_UID + 8, 3);
+
+        let id = chip_id(&emulator.bininfo(), &emulator).unwrap();
+        assert_eq!(id.part, "STM32F401xB/C");
+        assert_eq!(id.revision, "0x1000");
+        assert_eq!(id.serial, "000000030000000000000001");
+    }
+
+    #[test]
+    fn unsupported() {
+        let emulator = Emulator::new(512, 64, 1024, FamilyId::ATMEGA32);
+        assert!(matches!(
+            chip_id(&emulator.bininfo(), &emulator),
+            Err(UtilError::UnsupportedFamily {
+                family: Some(FamilyId::ATMEGA32)
+            })
+        ));
+    }
+}
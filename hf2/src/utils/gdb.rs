@@ -0,0 +1,505 @@
+use super::{decode_hex, UtilError};
+use crate::{read_memory, write_memory, BinInfoResponse, ReadWrite};
+use std::io::{self, Read, Write};
+
+/// Largest packet GDB may send, and the most payload put in a reply.
+const PACKET_SIZE: usize = 0x1000;
+
+/// ARM M-profile core registers. Nothing here halts the core, so they all read as zero.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>arm</architecture>
+  <feature name="org.gnu.gdb.arm.m-profile">
+    <reg name="r0" bitsize="32"/>
+    <reg name="r1" bitsize="32"/>
+    <reg name="r2" bitsize="32"/>
+    <reg name="r3" bitsize="32"/>
+    <reg name="r4" bitsize="32"/>
+    <reg name="r5" bitsize="32"/>
+    <reg name="r6" bitsize="32"/>
+    <reg name="r7" bitsize="32"/>
+    <reg name="r8" bitsize="32"/>
+    <reg name="r9" bitsize="32"/>
+    <reg name="r10" bitsize="32"/>
+    <reg name="r11" bitsize="32"/>
+    <reg name="r12" bitsize="32"/>
+    <reg name="sp" bitsize="32" type="data_ptr"/>
+    <reg name="lr" bitsize="32"/>
+    <reg name="pc" bitsize="32" type="code_ptr"/>
+    <reg name="xpsr" bitsize="32"/>
+  </feature>
+</target>
+"#;
+const REGISTERS: usize = 17;
+
+/// Stop reply for SIGTRAP, the core is reported as stopped though it keeps running.
+const STOPPED: &[u8] = b"S05";
+
+/// Host I/O errno values from the GDB protocol.
+const ENOENT: u32 = 2;
+const EBADF: u32 = 9;
+/// Only the elf can be opened, always under this descriptor.
+const ELF_FD: u32 = 1;
+
+/// Serve one GDB remote serial protocol connection until GDB detaches, kills or hangs up.
+///
+/// Memory packets go to the device through read_memory and write_memory, there is no run control or breakpoints. An elf given as its path and contents is named by qXfer:exec-file and served through host I/O, so GDB loads its symbols without being pointed at the file.
+pub fn serve(
+    stream: &mut (impl Read + Write),
+    elf: Option<(&str, &[u8])>,
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+) -> Result<(), UtilError> {
+    let mut session = Session {
+        elf,
+        no_ack: false,
+        done: false,
+        last: vec![],
+    };
+
+    while !session.done {
+        let packet = match session.receive(stream)? {
+            Some(packet) => packet,
+            None => return Ok(()),
+        };
+        log::debug!("gdb rx: {}", String::from_utf8_lossy(&packet));
+
+        if let Some(reply) = session.handle(&packet, bininfo, d) {
+            session.send(stream, reply)?;
+        }
+        // acks stop after the reply to this one
+        if packet == b"QStartNoAckMode" {
+            session.no_ack = true;
+        }
+    }
+    Ok(())
+}
+
+struct Session<'a> {
+    elf: Option<(&'a str, &'a [u8])>,
+    no_ack: bool,
+    done: bool,
+    /// Sent again if GDB nacks it.
+    last: Vec<u8>,
+}
+
+impl Session<'_> {
+    /// Next packet with escapes undone, None once the connection closes. Acks it unless no-ack mode was agreed.
+    fn receive(&mut self, stream: &mut (impl Read + Write)) -> Result<Option<Vec<u8>>, UtilError> {
+        loop {
+            match read_byte(stream)? {
+                None => return Ok(None),
+                Some(b'$') => (),
+                Some(b'-') => {
+                    let last = self.last.clone();
+                    self.send(stream, last)?;
+                    continue;
+                }
+                // ctrl-c, there is nothing to interrupt
+                Some(0x03) => {
+                    self.send(stream, b"S02".to_vec())?;
+                    continue;
+                }
+                // acks and line noise
+                Some(_) => continue,
+            }
+
+            let mut raw = vec![];
+            loop {
+                match read_byte(stream)? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => raw.push(byte),
+                }
+            }
+            let mut digits = [0_u8; 2];
+            for digit in digits.iter_mut() {
+                *digit = match read_byte(stream)? {
+                    None => return Ok(None),
+                    Some(byte) => byte,
+                };
+            }
+
+            let valid = std::str::from_utf8(&digits)
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                == Some(checksum(&raw));
+            if !self.no_ack {
+                write(stream, if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Some(unescape(&raw)));
+            }
+        }
+    }
+
+    /// Frame and send a reply, whose binary parts must already be escaped.
+    fn send(&mut self, stream: &mut impl Write, reply: Vec<u8>) -> Result<(), UtilError> {
+        log::debug!("gdb tx: {}", String::from_utf8_lossy(&reply));
+
+        let mut frame = vec![b'$'];
+        frame.extend_from_slice(&reply);
+        frame.extend_from_slice(format!("#{:02x}", checksum(&reply)).as_bytes());
+        self.last = reply;
+        write(stream, &frame)
+    }
+
+    /// Reply to a packet, None when none is expected. Anything unsupported gets the empty reply.
+    fn handle(
+        &mut self,
+        packet: &[u8],
+        bininfo: &BinInfoResponse,
+        d: &impl ReadWrite,
+    ) -> Option<Vec<u8>> {
+        let packet = String::from_utf8_lossy(packet);
+        let error = || b"E01".to_vec();
+
+        let reply = if packet.starts_with("qSupported") {
+            let mut features = format!(
+                "PacketSize={:x};QStartNoAckMode+;qXfer:features:read+",
+                PACKET_SIZE
+            );
+            if self.elf.is_some() {
+                features.push_str(";qXfer:exec-file:read+");
+            }
+            features.into_bytes()
+        } else if packet == "QStartNoAckMode" || packet.starts_with('H') {
+            b"OK".to_vec()
+        } else if packet == "?" || packet == "c" || packet == "s" {
+            STOPPED.to_vec()
+        } else if packet == "qC" {
+            b"QC1".to_vec()
+        } else if packet == "qAttached" {
+            b"1".to_vec()
+        } else if packet == "qfThreadInfo" {
+            b"m1".to_vec()
+        } else if packet == "qsThreadInfo" {
+            b"l".to_vec()
+        } else if packet == "g" {
+            "00000000".repeat(REGISTERS).into_bytes()
+        } else if let Some(register) = packet.strip_prefix('p') {
+            match u32::from_str_radix(register, 16) {
+                Ok(register) if (register as usize) < REGISTERS => b"00000000".to_vec(),
+                _ => error(),
+            }
+        } else if let Some(args) = packet.strip_prefix('m') {
+            match parse_pair(args) {
+                // GDB takes a short read, the reply has to fit a packet
+                Some((address, len)) => {
+                    let len = (len as usize).min(PACKET_SIZE / 2);
+                    match read_memory(d, bininfo, address, len) {
+                        Ok(data) => data
+                            .iter()
+                            .map(|b| format!("{:02x}", b))
+                            .collect::<String>()
+                            .into_bytes(),
+                        Err(e) => {
+                            log::debug!("gdb read failed: {}", e);
+                            error()
+                        }
+                    }
+                }
+                None => error(),
+            }
+        } else if let Some(args) = packet.strip_prefix('M') {
+            let write = args.split_once(':').and_then(|(pair, data)| {
+                let (address, len) = parse_pair(pair)?;
+                let data = decode_hex(data).filter(|data| data.len() == len as usize)?;
+                Some((address, data))
+            });
+            match write.map(|(address, data)| write_memory(d, bininfo, address, &data)) {
+                Some(Ok(())) => b"OK".to_vec(),
+                Some(Err(e)) => {
+                    log::debug!("gdb write failed: {}", e);
+                    error()
+                }
+                None => error(),
+            }
+        } else if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            xfer(TARGET_XML.as_bytes(), args).unwrap_or_else(error)
+        } else if let Some(args) = packet.strip_prefix("qXfer:exec-file:read:") {
+            // annex is the process id, there's only the one
+            match (self.elf, args.split_once(':')) {
+                (Some((path, _)), Some((_, args))) => {
+                    xfer(path.as_bytes(), args).unwrap_or_else(error)
+                }
+                _ => error(),
+            }
+        } else if let Some(args) = packet.strip_prefix("vFile:") {
+            self.host_io(args)
+        } else if packet == "D" {
+            self.done = true;
+            b"OK".to_vec()
+        } else if packet == "k" {
+            self.done = true;
+            return None;
+        } else {
+            vec![]
+        };
+        Some(reply)
+    }
+
+    /// Host I/O, read only access to the elf.
+    fn host_io(&self, args: &str) -> Vec<u8> {
+        let failed = |errno: u32| format!("F-1,{:x}", errno).into_bytes();
+        let elf = |fd: &str| match self.elf {
+            Some((_, data)) if u32::from_str_radix(fd, 16) == Ok(ELF_FD) => Some(data),
+            _ => None,
+        };
+
+        if args.starts_with("setfs:") {
+            b"F0".to_vec()
+        } else if let Some(args) = args.strip_prefix("open:") {
+            let name = args
+                .split(',')
+                .next()
+                .and_then(decode_hex)
+                .map(|name| String::from_utf8_lossy(&name).into_owned());
+            match (self.elf, name) {
+                (Some((path, _)), Some(name)) if name == path => {
+                    format!("F{:x}", ELF_FD).into_bytes()
+                }
+                _ => failed(ENOENT),
+            }
+        } else if let Some(args) = args.strip_prefix("pread:") {
+            let mut args = args.split(',');
+            let data = args.next().and_then(elf);
+            let count = args.next().and_then(|n| usize::from_str_radix(n, 16).ok());
+            let offset = args.next().and_then(|n| usize::from_str_radix(n, 16).ok());
+            match (data, count, offset) {
+                (Some(data), Some(count), Some(offset)) => {
+                    // escaping may double every byte
+                    let count = count.min(PACKET_SIZE / 2 - 16);
+                    let chunk = data.get(offset..).unwrap_or(&[]);
+                    let chunk = &chunk[..count.min(chunk.len())];
+                    let mut reply = format!("F{:x};", chunk.len()).into_bytes();
+                    reply.extend(escape(chunk));
+                    reply
+                }
+                _ => failed(EBADF),
+            }
+        } else if let Some(fd) = args.strip_prefix("fstat:") {
+            match elf(fd) {
+                Some(data) => {
+                    let stat = stat(data.len() as u64);
+                    let mut reply = format!("F{:x};", stat.len()).into_bytes();
+                    reply.extend(escape(&stat));
+                    reply
+                }
+                None => failed(EBADF),
+            }
+        } else if let Some(fd) = args.strip_prefix("close:") {
+            match elf(fd) {
+                Some(_) => b"F0".to_vec(),
+                None => failed(EBADF),
+            }
+        } else {
+            vec![]
+        }
+    }
+}
+
+/// GDB's struct stat for a read only regular file, big endian.
+fn stat(size: u64) -> Vec<u8> {
+    let mut stat = vec![];
+    // dev, ino, mode, nlink, uid, gid, rdev
+    for field in [0, 0, 0o100444, 1, 0, 0, 0_u32] {
+        stat.extend_from_slice(&field.to_be_bytes());
+    }
+    // size, blksize, blocks
+    for field in [size, 512, size.div_ceil(512)] {
+        stat.extend_from_slice(&field.to_be_bytes());
+    }
+    // atime, mtime, ctime
+    stat.extend_from_slice(&[0; 12]);
+    stat
+}
+
+/// Reply to a qXfer read of "offset,length" into data, 'm' if more follows, 'l' for the last part.
+fn xfer(data: &[u8], args: &str) -> Option<Vec<u8>> {
+    let (offset, len) = parse_pair(args)?;
+    let chunk = data.get(offset as usize..).unwrap_or(&[]);
+    let len = (len as usize).min(PACKET_SIZE / 2).min(chunk.len());
+
+    let mut reply = vec![if len < chunk.len() { b'm' } else { b'l' }];
+    reply.extend(escape(&chunk[..len]));
+    Some(reply)
+}
+
+/// Hex "address,length" as most packets carry it.
+fn parse_pair(args: &str) -> Option<(u32, u32)> {
+    let (address, len) = args.split_once(',')?;
+    Some((
+        u32::from_str_radix(address, 16).ok()?,
+        u32::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0_u8, |sum, b| sum.wrapping_add(*b))
+}
+
+/// Binary data in a packet escapes the framing characters with '}' and xor 0x20.
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for &byte in data {
+        match byte {
+            b'#' | b'$' | b'}' | b'*' => escaped.extend_from_slice(&[b'}', byte ^ 0x20]),
+            _ => escaped.push(byte),
+        }
+    }
+    escaped
+}
+
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+    while let Some(&byte) = bytes.next() {
+        match byte {
+            b'}' => unescaped.extend(bytes.next().map(|b| b ^ 0x20)),
+            _ => unescaped.push(byte),
+        }
+    }
+    unescaped
+}
+
+fn read_byte(stream: &mut impl Read) -> Result<Option<u8>, UtilError> {
+    let mut byte = [0_u8];
+    loop {
+        match stream.read(&mut byte) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(byte[0])),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(UtilError::Connection(e)),
+        }
+    }
+}
+
+fn write(stream: &mut impl Write, data: &[u8]) -> Result<(), UtilError> {
+    stream
+        .write_all(data)
+        .and_then(|_| stream.flush())
+        .map_err(UtilError::Connection)
+}
+
+#[cfg(all(test, feature = "emulator"))]
+mod tests {
+    use super::*;
+    use crate::emulator::Emulator;
+    use crate::FamilyId;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    const ELF: &[u8] = include_bytes!("testdata/blinky_1.44.0");
+
+    /// Scripted client, sends a packet and returns the reply with escapes undone.
+    fn exchange(stream: &mut TcpStream, packet: &str, ack: bool) -> Vec<u8> {
+        let frame = format!("${}#{:02x}", packet, checksum(packet.as_bytes()));
+        stream.write_all(frame.as_bytes()).unwrap();
+        if ack {
+            assert_eq!(read_byte(stream).unwrap(), Some(b'+'));
+        }
+
+        assert_eq!(read_byte(stream).unwrap(), Some(b'$'));
+        let mut raw = vec![];
+        loop {
+            match read_byte(stream).unwrap().unwrap() {
+                b'#' => break,
+                byte => raw.push(byte),
+            }
+        }
+        let digits = [
+            read_byte(stream).unwrap().unwrap(),
+            read_byte(stream).unwrap().unwrap(),
+        ];
+        assert_eq!(
+            u8::from_str_radix(std::str::from_utf8(&digits).unwrap(), 16).unwrap(),
+            checksum(&raw)
+        );
+        if ack {
+            stream.write_all(b"+").unwrap();
+        }
+        unescape(&raw)
+    }
+
+    #[test]
+    fn scripted_session() {
+        let emulator = Emulator::new(256, 64, 1024, FamilyId::ATSAMD21);
+        emulator.set_word(0x2000_0004, 0x1234_5678);
+        let bininfo = emulator.bininfo();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            let mut replies = vec![];
+
+            // a corrupted packet gets nacked
+            stream.write_all(b"$?#00").unwrap();
+            assert_eq!(read_byte(&mut stream).unwrap(), Some(b'-'));
+
+            for (packet, ack) in [
+                ("qSupported:multiprocess+;xmlRegisters=arm", true),
+                ("QStartNoAckMode", true),
+                ("?", false),
+                ("M20000001,3:aabbcc", false),
+                ("m20000000,8", false),
+                ("m20000003,2", false),
+                ("g", false),
+                ("qXfer:features:read:target.xml:0,40", false),
+                ("qXfer:exec-file:read::0,fff", false),
+                ("vFile:open:626c696e6b79,0,0", false),
+                ("vFile:pread:1,200,0", false),
+                ("vFile:pread:1,200,3ff0", false),
+                ("vFile:open:6f74686572,0,0", false),
+                ("vFile:close:1", false),
+                ("M00000000,1:00", false),
+                ("vMustReplyEmpty", false),
+            ] {
+                replies.push(exchange(&mut stream, packet, ack));
+            }
+            stream.write_all(b"$k#6b").unwrap();
+            replies
+        });
+
+        let (mut stream, _) = listener.accept().unwrap();
+        serve(&mut stream, Some(("blinky", ELF)), &bininfo, &emulator).unwrap();
+        let replies = client.join().unwrap();
+
+        let text = |i: usize| String::from_utf8_lossy(&replies[i]).into_owned();
+        assert!(text(0).contains("qXfer:exec-file:read+"));
+        assert_eq!(text(1), "OK");
+        assert_eq!(text(2), "S05");
+        assert_eq!(text(3), "OK");
+        assert_eq!(text(4), "00aabbcc78563412");
+        assert_eq!(text(5), "cc78");
+        assert_eq!(text(6), "0".repeat(8 * REGISTERS));
+        assert!(text(7).starts_with("m<?xml"));
+        assert_eq!(text(8), "lblinky");
+        assert_eq!(text(9), "F1");
+
+        let mut pread = b"F200;".to_vec();
+        pread.extend_from_slice(&ELF[..0x200]);
+        assert_eq!(replies[10], pread);
+        // short read at the end of the file
+        let mut pread = format!("F{:x};", ELF.len() - 0x3ff0).into_bytes();
+        pread.extend_from_slice(&ELF[0x3ff0..]);
+        assert_eq!(replies[11], pread);
+
+        assert_eq!(text(12), "F-1,2");
+        assert_eq!(text(13), "F0");
+        // flash isn't writable word by word
+        assert_eq!(text(14), "E01");
+        assert_eq!(text(15), "");
+        assert_eq!(emulator.word(0x2000_0000), 0xCCBB_AA00);
+    }
+
+    #[test]
+    fn escaping() {
+        let data = [b'#', 1, b'$', b'}', b'*', 2];
+        assert_eq!(escape(&data), b"}\x03\x01}\x04}]}\x0a\x02".to_vec());
+        assert_eq!(unescape(&escape(&data)), data.to_vec());
+    }
+}
@@ -0,0 +1,218 @@
+use super::{decode_hex, Image, UtilError};
+use std::path::PathBuf;
+use std::{
+    fs::File,
+    io::{Read, Write},
+};
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+/// Data records of an Intel HEX file, each at its absolute address, in file order.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Hex {
+    pub records: Vec<(u32, Vec<u8>)>,
+    /// From a start linear address record, or CS:IP of a start segment address record folded into one.
+    pub start_address: Option<u32>,
+}
+
+impl Hex {
+    /// Sparse image of the records, which may come in any order but must agree where they overlap.
+    pub fn to_image(&self) -> Result<Image, UtilError> {
+        let mut image = Image::new();
+        for (address, data) in &self.records {
+            image.add(*address, data)?;
+        }
+        image.start_address = self.start_address;
+        Ok(image)
+    }
+}
+
+/// Parse the text of an Intel HEX file, checking every record's checksum.
+pub fn parse(text: &str) -> Result<Hex, UtilError> {
+    let mut hex = Hex::default();
+    // upper bits from the last extended address record
+    let mut base: u32 = 0;
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |reason| UtilError::InvalidHex {
+            line: index + 1,
+            reason,
+        };
+
+        let bytes = line
+            .strip_prefix(':')
+            .ok_or_else(|| error("record doesn't start with ':'"))
+            .and_then(|digits| decode_hex(digits).ok_or_else(|| error("invalid hex digits")))?;
+
+        if bytes.len() < 5 || bytes.len() != 5 + bytes[0] as usize {
+            return Err(error("record length doesn't match its byte count"));
+        }
+        if bytes.iter().fold(0_u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(error("checksum mismatch"));
+        }
+
+        let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+        let data = &bytes[4..bytes.len() - 1];
+
+        match bytes[3] {
+            DATA => hex.records.push((base.wrapping_add(offset), data.to_vec())),
+            END_OF_FILE => return Ok(hex),
+            EXTENDED_SEGMENT_ADDRESS if data.len() == 2 => {
+                base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4;
+            }
+            EXTENDED_LINEAR_ADDRESS if data.len() == 2 => {
+                base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16;
+            }
+            START_SEGMENT_ADDRESS if data.len() == 4 => {
+                let cs = u16::from_be_bytes([data[0], data[1]]) as u32;
+                let ip = u16::from_be_bytes([data[2], data[3]]) as u32;
+                hex.start_address = Some((cs << 4) + ip);
+            }
+            START_LINEAR_ADDRESS if data.len() == 4 => {
+                hex.start_address = Some(u32::from_be_bytes([data[0], data[1], data[2], data[3]]));
+            }
+            EXTENDED_SEGMENT_ADDRESS
+            | EXTENDED_LINEAR_ADDRESS
+            | START_SEGMENT_ADDRESS
+            | START_LINEAR_ADDRESS => return Err(error("address record has wrong length")),
+            _ => return Err(error("unknown record type")),
+        }
+    }
+
+    Err(UtilError::InvalidHex {
+        line: text.lines().count(),
+        reason: "missing end of file record",
+    })
+}
+
+/// Intel HEX text of an image, 16 bytes per data record with extended linear address records as needed.
+pub fn to_string(image: &Image) -> String {
+    let mut text = String::new();
+    let mut upper = 0;
+
+    for segment in image.segments() {
+        let mut address = segment.address;
+        for chunk in segment.data.chunks(16) {
+            // a record can't cross a 64k boundary
+            let count = chunk.len().min(0x1_0000 - (address & 0xFFFF) as usize);
+            for chunk in [&chunk[..count], &chunk[count..]] {
+                if chunk.is_empty() {
+                    continue;
+                }
+                if address >> 16 != upper || text.is_empty() {
+                    upper = address >> 16;
+                    text += &record(0, EXTENDED_LINEAR_ADDRESS, &(upper as u16).to_be_bytes());
+                }
+                text += &record(address as u16, DATA, chunk);
+                address = address.wrapping_add(chunk.len() as u32);
+            }
+        }
+    }
+    if let Some(start_address) = image.start_address {
+        text += &record(0, START_LINEAR_ADDRESS, &start_address.to_be_bytes());
+    }
+    text += &record(0, END_OF_FILE, &[]);
+    text
+}
+
+fn record(offset: u16, kind: u8, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend_from_slice(&offset.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend_from_slice(data);
+    let checksum = bytes
+        .iter()
+        .fold(0_u8, |sum, b| sum.wrapping_add(*b))
+        .wrapping_neg();
+    bytes.push(checksum);
+
+    let digits: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    format!(":{}\n", digits)
+}
+
+/// Write an image as an Intel HEX file.
+pub fn write(path: PathBuf, image: &Image) -> Result<(), UtilError> {
+    File::create(path)
+        .and_then(|mut file| file.write_all(to_string(image).as_bytes()))
+        .map_err(UtilError::WriteFile)
+}
+
+/// Read and parse an Intel HEX file.
+pub fn read(path: PathBuf) -> Result<Hex, UtilError> {
+    let mut file = File::open(path).map_err(UtilError::File)?;
+    let mut buffer = String::new();
+    file.read_to_string(&mut buffer).map_err(UtilError::File)?;
+
+    parse(&buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn linear_and_segment_records() {
+        let hex = parse(
+            ":020000040000FA\n\
+             :0400100001020304E2\n\
+             :020000021000EC\n\
+             :02000000AABB99\n\
+             :04000005000041C1F5\n\
+             :00000001FF\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            hex.records,
+            vec![(0x0010, vec![1, 2, 3, 4]), (0x10000, vec![0xAA, 0xBB])]
+        );
+        assert_eq!(hex.start_address, Some(0x41C1));
+
+        let image = hex.to_image().unwrap();
+        assert_eq!(image.segments().len(), 2);
+        assert_eq!(image.address(), Some(0x10));
+        assert_eq!(image.start_address, Some(0x41C1));
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut image = Image::from_bin(&(0..40).collect::<Vec<u8>>(), 0x0800_FFF0).unwrap();
+        image.add(0x2000_0000, &[0xAA; 3]).unwrap();
+        image.start_address = Some(0x0800_0101);
+
+        let text = to_string(&image);
+        assert!(text.starts_with(":020000040800F2\n:10FFF000"));
+        assert!(text.ends_with(":00000001FF\n"));
+        assert_eq!(parse(&text).unwrap().to_image().unwrap(), image);
+    }
+
+    #[test]
+    fn upper_linear_address() {
+        let hex = parse(":020000040800F2\r\n:0100000055AA\r\n:00000001FF\r\n").unwrap();
+        assert_eq!(hex.records, vec![(0x0800_0000, vec![0x55])]);
+    }
+
+    #[test]
+    fn bad_records() {
+        let line = |text| match parse(text) {
+            Err(UtilError::InvalidHex { line, .. }) => line,
+            other => panic!("{:?}", other),
+        };
+
+        // checksum off by one
+        assert_eq!(line(":0100000055AB\n:00000001FF\n"), 1);
+        // byte count says 2 but carries 1
+        assert_eq!(line(":020000005549\n:00000001FF\n"), 1);
+        assert_eq!(line(":0100000055AA\n0100000055AA\n"), 2);
+        assert_eq!(line(":0100000055AA\n"), 1);
+    }
+}
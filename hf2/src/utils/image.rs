@@ -0,0 +1,262 @@
+use super::UtilError;
+use std::collections::BTreeMap;
+
+/// Bytes to place starting at address.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    /// One past the last byte, u64 so a segment may end at the top of the address space.
+    pub fn end(&self) -> u64 {
+        self.address as u64 + self.data.len() as u64
+    }
+}
+
+/// Sparse memory image, segments are kept sorted by address, never overlap and adjacent ones are merged.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Image {
+    segments: Vec<Segment>,
+    /// Entry point, if the file carried one.
+    pub start_address: Option<u32>,
+}
+
+impl Image {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Image of a single contiguous binary.
+    pub fn from_bin(binary: &[u8], address: u32) -> Result<Self, UtilError> {
+        let mut image = Self::new();
+        image.add(address, binary)?;
+        Ok(image)
+    }
+
+    /// Place data at address, in any order. Overlapping an existing segment is only allowed where the bytes agree.
+    pub fn add(&mut self, address: u32, data: &[u8]) -> Result<(), UtilError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let end = address as u64 + data.len() as u64;
+        if end > 1 << 32 {
+            return Err(UtilError::InvalidBinary);
+        }
+
+        // records usually arrive in order, each one continuing the last
+        if let Some(segment) = self.segments.last_mut() {
+            if segment.end() == address as u64 {
+                segment.data.extend_from_slice(data);
+                return Ok(());
+            }
+        }
+
+        // every segment touching or overlapping the new one gets merged into it
+        let first = self
+            .segments
+            .partition_point(|segment| segment.end() < address as u64);
+        let last = self
+            .segments
+            .partition_point(|segment| (segment.address as u64) <= end);
+
+        // checked before anything changes so a conflict leaves the image untouched
+        for segment in &self.segments[first..last] {
+            let overlap_start = segment.address.max(address);
+            let overlap_end = segment.end().min(end);
+            if overlap_start as u64 >= overlap_end {
+                continue;
+            }
+            let len = (overlap_end - overlap_start as u64) as usize;
+            let ours = &segment.data[(overlap_start - segment.address) as usize..][..len];
+            let theirs = &data[(overlap_start - address) as usize..][..len];
+            if let Some(i) = ours.iter().zip(theirs).position(|(a, b)| a != b) {
+                return Err(UtilError::Overlap {
+                    address: overlap_start + i as u32,
+                });
+            }
+        }
+
+        let start = self.segments[first..last]
+            .first()
+            .map_or(address, |segment| segment.address.min(address));
+        let merged_end = self.segments[first..last]
+            .last()
+            .map_or(end, |segment| segment.end().max(end));
+
+        // grow the first segment in place when it starts at or before the new data, its bytes are taken so the copy below skips them
+        let mut merged = match self.segments.get_mut(first) {
+            Some(segment) if first < last && segment.address == start => Segment {
+                address: start,
+                data: core::mem::take(&mut segment.data),
+            },
+            _ => Segment {
+                address: start,
+                data: Vec::new(),
+            },
+        };
+        merged
+            .data
+            .resize((merged_end - start as u64) as usize, 0x0);
+
+        for segment in &self.segments[first..last] {
+            merged.data[(segment.address - start) as usize..][..segment.data.len()]
+                .copy_from_slice(&segment.data);
+        }
+        // the overlap check means the new data may overwrite what's there
+        merged.data[(address - start) as usize..][..data.len()].copy_from_slice(data);
+
+        self.segments.splice(first..last, core::iter::once(merged));
+        Ok(())
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Bytes of data, not counting the gaps between segments.
+    pub fn len(&self) -> usize {
+        self.segments.iter().map(|segment| segment.data.len()).sum()
+    }
+
+    /// Lowest address with data.
+    pub fn address(&self) -> Option<u32> {
+        self.segments.first().map(|segment| segment.address)
+    }
+
+    /// Contiguous binary and the address it starts at, gaps are zero. Beware segments far apart make this huge.
+    pub fn to_bin(&self) -> Result<(Vec<u8>, u32), UtilError> {
+        let (first, last) = match (self.segments.first(), self.segments.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Err(UtilError::InvalidBinary),
+        };
+
+        let mut binary = vec![0x0; (last.end() - first.address as u64) as usize];
+        for segment in &self.segments {
+            binary[(segment.address - first.address) as usize..][..segment.data.len()]
+                .copy_from_slice(&segment.data);
+        }
+        Ok((binary, first.address))
+    }
+
+    /// Only the pages holding data, keyed by page address, parts of a page no segment covers are zero.
+    pub fn pages(&self, page_size: u32) -> BTreeMap<u32, Vec<u8>> {
+        self.pages_with_padding(page_size, 0x0)
+    }
+
+    /// Like pages, with parts of a page no segment covers set to padding.
+    pub fn pages_with_padding(&self, page_size: u32, padding: u8) -> BTreeMap<u32, Vec<u8>> {
+        let mut pages: BTreeMap<u32, Vec<u8>> = BTreeMap::new();
+
+        for segment in &self.segments {
+            let mut address = segment.address;
+            let mut data = segment.data.as_slice();
+
+            // a segment may start and end part way into a page
+            while !data.is_empty() {
+                let offset = address % page_size;
+                let count = data.len().min((page_size - offset) as usize);
+
+                let page = pages
+                    .entry(address - offset)
+                    .or_insert_with(|| vec![padding; page_size as usize]);
+                page[offset as usize..][..count].copy_from_slice(&data[..count]);
+
+                address = address.wrapping_add(count as u32);
+                data = &data[count..];
+            }
+        }
+        pages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sorted_and_merged() {
+        let mut image = Image::new();
+        image.add(0x2000, &[3, 4]).unwrap();
+        image.add(0x1000, &[1, 2]).unwrap();
+        // adjacent to the first
+        image.add(0x1002, &[5]).unwrap();
+        // overlapping with agreeing bytes, bridging nothing
+        image.add(0x1001, &[2, 5, 6]).unwrap();
+
+        assert_eq!(
+            image.segments(),
+            &[
+                Segment {
+                    address: 0x1000,
+                    data: vec![1, 2, 5, 6]
+                },
+                Segment {
+                    address: 0x2000,
+                    data: vec![3, 4]
+                }
+            ]
+        );
+        assert_eq!(image.len(), 6);
+
+        // bridging both
+        image.add(0x1004, &vec![7; 0x1000 - 4]).unwrap();
+        assert_eq!(image.segments().len(), 1);
+        assert_eq!(image.len(), 0x1002);
+    }
+
+    #[test]
+    fn conflicting_overlap() {
+        let mut image = Image::from_bin(&[1, 2, 3], 0x100).unwrap();
+        assert!(matches!(
+            image.add(0xFF, &[0, 1, 9]),
+            Err(UtilError::Overlap { address: 0x101 })
+        ));
+        // left untouched
+        assert_eq!(image.segments()[0].data, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn appended_records() {
+        // 16 byte records like ihex and srec, 4 MiB would take minutes when every add copied the image
+        let mut image = Image::new();
+        for i in 0..0x40000_u32 {
+            image.add(0x4000 + i * 16, &[i as u8; 16]).unwrap();
+        }
+        assert_eq!(image.segments().len(), 1);
+        assert_eq!(image.len(), 0x40_0000);
+
+        // extending a segment which isn't the last
+        image.add(0x1000, &[1; 4]).unwrap();
+        image.add(0x1004, &[2; 4]).unwrap();
+        image.add(0x1002, &[1, 1, 2]).unwrap();
+        assert_eq!(image.segments()[0].data, vec![1, 1, 1, 1, 2, 2, 2, 2]);
+        assert_eq!(image.segments().len(), 2);
+    }
+
+    #[test]
+    fn only_pages_with_data() {
+        let mut image = Image::new();
+        image.add(0x4100, &[1; 300]).unwrap();
+        image.add(0x2000_0000, &[2; 4]).unwrap();
+
+        let pages = image.pages(512);
+        assert_eq!(
+            pages.keys().copied().collect::<Vec<_>>(),
+            vec![0x4000, 0x4200, 0x2000_0000]
+        );
+        assert_eq!(pages[&0x4000][0xFF], 0);
+        assert_eq!(pages[&0x4000][0x100], 1);
+        assert_eq!(pages[&0x4200][0x2B], 1);
+        assert_eq!(pages[&0x4200][0x2C], 0);
+        assert_eq!(&pages[&0x2000_0000][..5], &[2, 2, 2, 2, 0]);
+
+        let pages = image.pages_with_padding(512, 0xFF);
+        assert_eq!(&pages[&0x2000_0000][..5], &[2, 2, 2, 2, 0xFF]);
+    }
+}
@@ -1,127 +1,513 @@
 use super::{
-    checksum_pages, reset_into_app, start_flash, write_flash_page, BinInfoMode, BinInfoResponse,
-    Error,
+    checksum_pages, read_memory, reset_into_app, start_flash, write_flash_page, BinInfoMode,
+    BinInfoResponse, Error, FamilyId, ReadWrite,
 };
+use core::fmt;
 use crc_any::CRCu16;
 use goblin::elf::program_header::*;
-use hidapi::HidDevice;
+use std::collections::BTreeMap;
 use std::path::PathBuf;
-use std::{fs::File, io::Read};
+use std::{
+    fs::File,
+    io::{Read, Write},
+};
+
+/// UF2 file format reader and writer
+pub mod uf2;
+
+/// Intel HEX file parser
+pub mod ihex;
+
+/// Motorola S-record file parser
+pub mod srec;
+
+/// GDB remote serial protocol server for reading and writing memory
+pub mod gdb;
+
+/// Part, revision and unique serial number from each family's identification registers
+mod chipid;
+pub use chipid::*;
+
+/// CMSIS-SVD peripheral register descriptions
+pub mod svd;
+
+/// Variables and their fields found by name in an elf
+mod symbols;
+pub use symbols::*;
+
+/// Sparse memory image built from address and data segments
+mod image;
+pub use image::*;
+
+/// Progress events while flashing and verifying
+mod observer;
+pub use observer::*;
+
+/// Progress bar rendering observer events, shared by the command line tools
+#[cfg(feature = "progress")]
+mod progress;
+#[cfg(feature = "progress")]
+pub use progress::*;
+
+/// Times a command is tried before giving up, and times pages are rewritten if they don't verify.
+const ATTEMPTS: usize = 3;
 
 #[derive(Debug)]
 pub enum UtilError {
-    File,
+    /// Opening or reading an input file failed.
+    File(std::io::Error),
+    /// Creating or writing an output file failed.
+    WriteFile(std::io::Error),
     InvalidBinary,
-    Elf,
-    Internal,
-    Communication,
+    Elf(goblin::error::Error),
+    /// Talking to the device failed, the source says which command and address
+    Communication(Error),
     ContentsDifferent,
+    InvalidUf2 {
+        block: usize,
+        reason: &'static str,
+    },
+    InvalidHex {
+        line: usize,
+        reason: &'static str,
+    },
+    InvalidSrec {
+        line: usize,
+        reason: &'static str,
+    },
+    /// A raw binary was given without the address to place it at.
+    MissingAddress,
+    /// An address was given for a file which carries its own.
+    UnexpectedAddress,
+    /// Two segments place different bytes at the same address.
+    Overlap {
+        address: u32,
+    },
+    /// The file was built for another chip family than the device's.
+    FamilyMismatch {
+        file: FamilyId,
+        device: FamilyId,
+    },
+    /// Reading from or writing to the debugger's connection failed.
+    Connection(std::io::Error),
+    /// A variable couldn't be found in the elf.
+    Symbol {
+        name: String,
+        reason: &'static str,
+    },
+    /// A value doesn't fit the variable it's written to.
+    ValueTooLarge {
+        value: i64,
+        size: u32,
+    },
+    Xml(roxmltree::Error),
+    InvalidSvd {
+        element: String,
+        reason: &'static str,
+    },
+    /// Not a number or enumerated value name that fits the field.
+    InvalidFieldValue {
+        field: String,
+        value: String,
+    },
+    /// Which registers identify the chip isn't known for this family, or the device didn't say.
+    UnsupportedFamily {
+        family: Option<FamilyId>,
+    },
 }
 
 impl From<Error> for UtilError {
     fn from(err: Error) -> UtilError {
-        match err {
-            Error::Parse | Error::Transmission => UtilError::Communication,
-            _ => UtilError::Internal,
+        UtilError::Communication(err)
+    }
+}
+
+impl fmt::Display for UtilError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UtilError::File(_) => write!(f, "couldn't read file"),
+            UtilError::WriteFile(_) => write!(f, "couldn't write file"),
+            UtilError::InvalidBinary => write!(f, "binary is empty or past the end of memory"),
+            UtilError::MissingAddress => write!(f, "raw binaries need a base address"),
+            UtilError::UnexpectedAddress => {
+                write!(f, "file carries its own addresses, drop the base address")
+            }
+            UtilError::Elf(_) => write!(f, "couldn't parse elf"),
+            UtilError::Communication(_) => write!(f, "device communication failed"),
+            UtilError::ContentsDifferent => write!(f, "flash contents differ from binary"),
+            UtilError::InvalidUf2 { block, reason } => {
+                write!(f, "invalid uf2 block {}: {}", block, reason)
+            }
+            UtilError::InvalidHex { line, reason } => {
+                write!(f, "invalid hex record on line {}: {}", line, reason)
+            }
+            UtilError::InvalidSrec { line, reason } => {
+                write!(f, "invalid s-record on line {}: {}", line, reason)
+            }
+            UtilError::Overlap { address } => {
+                write!(
+                    f,
+                    "segments overlap with different data at 0x{:08X}",
+                    address
+                )
+            }
+            UtilError::FamilyMismatch { file, device } => write!(
+                f,
+                "file is for family {:?} but device is {:?}",
+                file, device
+            ),
+            UtilError::Connection(_) => write!(f, "debugger connection failed"),
+            UtilError::Symbol { name, reason } => write!(f, "symbol {}: {}", name, reason),
+            UtilError::ValueTooLarge { value, size } => {
+                write!(f, "{} doesn't fit in {} bytes", value, size)
+            }
+            UtilError::Xml(_) => write!(f, "couldn't parse svd"),
+            UtilError::InvalidSvd { element, reason } => {
+                write!(f, "invalid svd at {}: {}", element, reason)
+            }
+            UtilError::InvalidFieldValue { field, value } => {
+                write!(f, "invalid value {} for field {}", value, field)
+            }
+            UtilError::UnsupportedFamily {
+                family: Some(family),
+            } => {
+                write!(f, "no chip id registers known for family {:?}", family)
+            }
+            UtilError::UnsupportedFamily { family: None } => {
+                write!(f, "device doesn't report its family")
+            }
         }
     }
 }
 
-/// Returns a contiguous bin with 0s between non-contiguous sections and starting address from an elf.
+impl std::error::Error for UtilError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            UtilError::File(e) | UtilError::WriteFile(e) => Some(e),
+            UtilError::Elf(e) => Some(e),
+            UtilError::Communication(e) => Some(e),
+            UtilError::Connection(e) => Some(e),
+            UtilError::Xml(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Returns a contiguous bin with 0s between non-contiguous sections and starting address from an elf. Segments far apart make this huge, prefer elf_to_image.
 pub fn elf_to_bin(path: PathBuf) -> Result<(Vec<u8>, u32), UtilError> {
-    let mut file = File::open(path).map_err(|_| UtilError::File)?;
+    elf_to_image(path)?.to_bin()
+}
+
+/// Returns the loadable segments of an elf at their physical addresses, in any order they appear.
+pub fn elf_to_image(path: PathBuf) -> Result<Image, UtilError> {
+    let mut file = File::open(path).map_err(UtilError::File)?;
     let mut buffer = vec![];
-    file.read_to_end(&mut buffer).map_err(|_| UtilError::File)?;
-
-    let binary = goblin::elf::Elf::parse(buffer.as_slice()).map_err(|_| UtilError::Elf)?;
-
-    let mut start_address: u64 = 0;
-    let mut last_address: u64 = 0;
-
-    let mut data = vec![];
-    for (i, ph) in binary
-        .program_headers
-        .iter()
-        .filter(|ph| {
-            ph.p_type == PT_LOAD
-                && ph.p_filesz > 0
-                && ph.p_offset >= binary.header.e_ehsize as u64
-                && ph.is_read()
-        })
-        .enumerate()
-    {
-        // first time through grab the starting physical address
-        if i == 0 {
-            start_address = ph.p_paddr;
-        }
-        // on subsequent passes, if there's a gap between this section and the
-        // previous one, fill it with zeros
-        else {
-            let difference = (ph.p_paddr - last_address) as usize;
-            data.resize(data.len() + difference, 0x0);
+    file.read_to_end(&mut buffer).map_err(UtilError::File)?;
+
+    let binary = goblin::elf::Elf::parse(buffer.as_slice()).map_err(UtilError::Elf)?;
+
+    let mut image = Image::new();
+    for ph in binary.program_headers.iter().filter(|ph| {
+        ph.p_type == PT_LOAD
+            && ph.p_filesz > 0
+            && ph.p_offset >= binary.header.e_ehsize as u64
+            && ph.is_read()
+    }) {
+        let data = buffer
+            .get(ph.p_offset as usize..)
+            .and_then(|data| data.get(..ph.p_filesz as usize))
+            .ok_or(UtilError::InvalidBinary)?;
+
+        image.add(ph.p_paddr as u32, data)?;
+    }
+    image.start_address = Some(binary.entry as u32);
+
+    Ok(image)
+}
+
+/// Load an ELF, Intel HEX, S-record, UF2 or raw binary file. The format is picked by extension, raw binaries are placed at address. Without an extension, a file is raw if given an address and picked by contents otherwise. Formats carrying their own addresses refuse one.
+pub fn load(path: PathBuf, address: Option<u32>) -> Result<Image, UtilError> {
+    let mut file = File::open(&path).map_err(UtilError::File)?;
+    let mut buffer = vec![];
+    file.read_to_end(&mut buffer).map_err(UtilError::File)?;
+
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+
+    let addressed = match extension.as_deref() {
+        Some("bin") => return Image::from_bin(&buffer, address.ok_or(UtilError::MissingAddress)?),
+        Some("hex") | Some("ihex") | Some("srec") | Some("s19") | Some("s28") | Some("s37")
+        | Some("mot") | Some("elf") | Some("uf2") => true,
+        _ => false,
+    };
+    if addressed && address.is_some() {
+        return Err(UtilError::UnexpectedAddress);
+    }
+
+    match extension.as_deref() {
+        Some("hex") | Some("ihex") => return ihex::read(path)?.to_image(),
+        Some("srec") | Some("s19") | Some("s28") | Some("s37") | Some("mot") => {
+            return srec::read(path)?.to_image()
         }
+        Some("elf") => return elf_to_image(path),
+        Some("uf2") => return uf2::to_image(&uf2::read(path)?),
+        _ => (),
+    }
+
+    if let Some(address) = address {
+        Image::from_bin(&buffer, address)
+    } else if buffer.starts_with(b"\x7fELF") {
+        elf_to_image(path)
+    } else if buffer.starts_with(b":") {
+        ihex::read(path)?.to_image()
+    } else if buffer.len() > 1 && buffer[0] == b'S' && buffer[1].is_ascii_digit() {
+        srec::read(path)?.to_image()
+    } else if buffer.starts_with(b"UF2\n") {
+        uf2::to_image(&uf2::read(path)?)
+    } else {
+        Err(UtilError::MissingAddress)
+    }
+}
 
-        data.extend_from_slice(&buffer[ph.p_offset as usize..][..ph.p_filesz as usize]);
+/// Save an image as UF2, tagged with family_id if given, Intel HEX or a raw binary, picked by extension. A raw binary fills gaps between segments with zeros.
+pub fn save(path: PathBuf, image: &Image, family_id: Option<FamilyId>) -> Result<(), UtilError> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("uf2") => uf2::write(path, &uf2::from_image(image, uf2::PAYLOAD_SIZE, family_id)?),
+        Some("hex") | Some("ihex") => ihex::write(path, image),
+        _ => {
+            let (binary, _) = image.to_bin()?;
+            File::create(path)
+                .and_then(|mut file| file.write_all(&binary))
+                .map_err(UtilError::WriteFile)
+        }
+    }
+}
 
-        last_address = ph.p_paddr + ph.p_filesz;
+/// Decode pairs of hex digits, as both hex and s-record text carry bytes.
+fn decode_hex(digits: &str) -> Option<Vec<u8>> {
+    if !digits.len().is_multiple_of(2) {
+        return None;
     }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(digits.get(i..i + 2)?, 16).ok())
+        .collect()
+}
 
-    Ok((data, start_address as u32))
+/// How to check what's on the device, both before writing to skip unchanged pages and after.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub enum VerifyMode {
+    /// Compare CRC-16 of every page using CHKSUM PAGES.
+    #[default]
+    Crc,
+    /// Read every page back using READ WORDS and compare byte for byte. Slower, but catches CRC collisions and works on bootloaders without CHKSUM PAGES.
+    Readback,
+    /// Write every page and don't check anything.
+    None,
+}
+
+impl core::str::FromStr for VerifyMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "crc" => Ok(VerifyMode::Crc),
+            "readback" => Ok(VerifyMode::Readback),
+            "none" => Ok(VerifyMode::None),
+            _ => Err(format!(
+                "unknown verify mode {}, use crc, readback or none",
+                s
+            )),
+        }
+    }
 }
 
 /// Flash, Verify and restart into app.
 pub fn flash_bin(
     binary: &[u8],
     address: u32,
+    mode: VerifyMode,
     bininfo: &BinInfoResponse,
-    d: &HidDevice,
-) -> Result<(), UtilError> {
-    if binary.is_empty() {
+    d: &impl ReadWrite,
+) -> Result<FlashReport, UtilError> {
+    flash_image(&Image::from_bin(binary, address)?, mode, bininfo, d, &())
+}
+
+/// Flash only the pages holding data, Verify and restart into app. Flash between segments is left alone.
+pub fn flash_image(
+    image: &Image,
+    mode: VerifyMode,
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+    observer: &impl Observer,
+) -> Result<FlashReport, UtilError> {
+    if image.is_empty() {
         return Err(UtilError::InvalidBinary);
     }
 
-    let mut binary = binary.to_owned();
-
-    // pad zeros to page size
-    // add divisor-1 to dividend to round up
-    let padded_num_pages =
-        (binary.len() as u32 + (bininfo.flash_page_size - 1)) / bininfo.flash_page_size;
-
-    let padded_size = padded_num_pages * bininfo.flash_page_size;
+    let pages = Pages::new(image, bininfo.flash_page_size);
     log::debug!(
-        "binary is {} bytes, padding to {} bytes",
-        binary.len(),
-        padded_size
+        "image is {} bytes in {} segments, padding to {} pages",
+        image.len(),
+        image.segments().len(),
+        pages.data.len()
     );
-    for _i in 0..(padded_size as usize - binary.len()) {
-        binary.push(0x0);
+
+    flash_pages(&pages, mode, bininfo, d, observer)
+}
+
+/// Flash the main flash blocks of a UF2 file, Verify and restart into app. Blocks may be sparse and in any order.
+pub fn flash_uf2(
+    blocks: &[uf2::Block],
+    mode: VerifyMode,
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+    observer: &impl Observer,
+) -> Result<FlashReport, UtilError> {
+    check_family(blocks, bininfo)?;
+
+    flash_image(&uf2::to_image(blocks)?, mode, bininfo, d, observer)
+}
+
+/// Errors if any block is tagged with a family other than the device's.
+fn check_family(blocks: &[uf2::Block], bininfo: &BinInfoResponse) -> Result<(), UtilError> {
+    if let Some(device) = bininfo.family_id {
+        if let Some(file) = blocks
+            .iter()
+            .filter_map(|block| block.family_id())
+            .find(|&family_id| family_id != device)
+        {
+            return Err(UtilError::FamilyMismatch { file, device });
+        }
+    }
+    Ok(())
+}
+
+/// Pages of an image as they get flashed, padded with zeros, and padded the way erased flash reads to tell padding from data.
+struct Pages {
+    data: BTreeMap<u32, Vec<u8>>,
+    erased: BTreeMap<u32, Vec<u8>>,
+}
+
+impl Pages {
+    fn new(image: &Image, page_size: u32) -> Self {
+        Self {
+            data: image.pages(page_size),
+            erased: image.pages_with_padding(page_size, 0xFF),
+        }
     }
+}
+
+/// Pages a flash covered, and how many of them it had to write.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct FlashReport {
+    /// Pages holding data.
+    pub pages: usize,
+    /// Pages already matching the device, never written.
+    pub skipped: usize,
+    /// Pages written, counting rewrites of pages that didn't verify.
+    pub written: usize,
+}
 
+/// Write the pages the device doesn't already hold, Verify and restart into app.
+fn flash_pages(
+    pages: &Pages,
+    mode: VerifyMode,
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+    observer: &impl Observer,
+) -> Result<FlashReport, UtilError> {
     if bininfo.mode != BinInfoMode::Bootloader {
-        let _ = start_flash(d).map_err(UtilError::from)?;
+        observer.phase(Phase::StartFlash);
+        retry(observer, || start_flash(d))?;
     }
-    flash(&binary, address, bininfo, d)?;
 
-    match verify(&binary, address, bininfo, d) {
-        Ok(false) => return Err(UtilError::ContentsDifferent),
-        Err(e) => return Err(e),
-        Ok(true) => (),
+    let total = pages.data.len();
+    let addresses = if mode == VerifyMode::None {
+        pages.data.keys().copied().collect()
+    } else {
+        // most pages are unchanged between builds, checking is far cheaper than writing
+        observer.phase(Phase::Compare { pages: total });
+        mismatched_addresses(verify(pages, mode, bininfo, d, observer)?)
     };
+    let mut report = FlashReport {
+        pages: total,
+        skipped: total - addresses.len(),
+        written: addresses.len(),
+    };
+    log::debug!("{} of {} pages unchanged", report.skipped, report.pages);
+    observer.skipped(report.skipped);
+    flash(&pages.data, &addresses, d, observer)?;
+
+    // a page can go missing without any error, so rewrite whatever doesn't verify
+    let mut attempt = 1;
+    loop {
+        if mode == VerifyMode::None {
+            break;
+        }
+        observer.phase(Phase::Verify { pages: total });
+        let mismatches = mismatched_addresses(verify(pages, mode, bininfo, d, observer)?);
+        if mismatches.is_empty() {
+            break;
+        }
+        if attempt >= ATTEMPTS {
+            return Err(UtilError::ContentsDifferent);
+        }
+        log::warn!("{} pages didn't verify, rewriting", mismatches.len());
+        flash(&pages.data, &mismatches, d, observer)?;
+        report.written += mismatches.len();
+        attempt += 1;
+    }
 
-    reset_into_app(d).map_err(UtilError::from)
+    observer.phase(Phase::Reset);
+    retry(observer, || reset_into_app(d))?;
+    Ok(report)
+}
+
+/// Run a command up to ATTEMPTS times. Whatever a failed attempt left unread is drained by the next one.
+fn retry<T>(
+    observer: &impl Observer,
+    mut command: impl FnMut() -> Result<T, Error>,
+) -> Result<T, Error> {
+    let mut attempt = 1;
+    loop {
+        match command() {
+            Err(e) if attempt < ATTEMPTS => {
+                log::warn!("attempt {} failed with {:?}, retrying", attempt, e);
+                observer.retry(attempt, &e);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
 }
 
-/// Flashes binary writing a single page at a time.
+/// Flashes the pages at the given addresses, writing a single page at a time.
 fn flash(
-    binary: &[u8],
-    address: u32,
-    bininfo: &BinInfoResponse,
-    d: &HidDevice,
+    pages: &BTreeMap<u32, Vec<u8>>,
+    addresses: &[u32],
+    d: &impl ReadWrite,
+    observer: &impl Observer,
 ) -> Result<(), UtilError> {
-    for (page_index, page) in binary.chunks(bininfo.flash_page_size as usize).enumerate() {
-        let target_address = address + bininfo.flash_page_size * page_index as u32;
+    let page_size = pages.values().next().map_or(0, |page| page.len());
+    observer.phase(Phase::Write {
+        pages: addresses.len(),
+        bytes: addresses.len() * page_size,
+    });
+
+    for target_address in addresses {
+        let page = &pages[target_address];
 
-        let _ = write_flash_page(d, target_address, page.to_vec()).map_err(UtilError::from)?;
+        retry(observer, || {
+            write_flash_page(d, *target_address, page.clone())
+        })?;
+        observer.written(*target_address, page.len());
     }
     Ok(())
 }
@@ -129,70 +515,215 @@ fn flash(
 pub fn verify_bin(
     binary: &[u8],
     address: u32,
+    mode: VerifyMode,
     bininfo: &BinInfoResponse,
-    d: &HidDevice,
+    d: &impl ReadWrite,
 ) -> Result<(), UtilError> {
-    let mut binary = binary.to_owned();
+    if verify_image(&Image::from_bin(binary, address)?, mode, bininfo, d, &())?.is_ok() {
+        Ok(())
+    } else {
+        Err(UtilError::ContentsDifferent)
+    }
+}
 
-    // pad zeros to page size
-    // add divisor-1 to dividend to round up
-    let padded_num_pages =
-        (binary.len() as u32 + (bininfo.flash_page_size - 1)) / bininfo.flash_page_size;
+/// A page whose contents on the device differ from the image.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Mismatch {
+    pub target_address: u32,
+    /// CRC of the page in the image, padded with zeros as it would be flashed.
+    pub expected: u16,
+    /// CRC of the page on the device.
+    pub actual: u16,
+    /// Only the parts of the page the image doesn't cover differ, so the data is right. With Crc this is a guess, the device matches once padding is taken as erased flash.
+    pub padding: bool,
+    /// Address of the first differing byte, only known with Readback.
+    pub first_difference: Option<u32>,
+}
 
-    let padded_size = padded_num_pages * bininfo.flash_page_size;
+/// Result of checking every page of an image against the device.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct VerifyReport {
+    /// Pages holding data.
+    pub pages: usize,
+    pub mismatches: Vec<Mismatch>,
+}
 
-    for _i in 0..(padded_size as usize - binary.len()) {
-        binary.push(0x0);
+impl VerifyReport {
+    /// Nothing differs.
+    pub fn is_ok(&self) -> bool {
+        self.mismatches.is_empty()
     }
 
-    match verify(&binary, address, bininfo, d) {
-        Ok(false) => Err(UtilError::ContentsDifferent),
-        Err(e) => Err(e),
-        Ok(true) => Ok(()),
+    /// Only padding differs, every byte of data is on the device.
+    pub fn data_matches(&self) -> bool {
+        self.mismatches.iter().all(|mismatch| mismatch.padding)
     }
 }
 
-/// Verifys checksum of binary.
-fn verify(
-    binary: &[u8],
-    address: u32,
+/// Verify only the pages holding data, reporting every page that differs. Errors only if talking to the device fails.
+pub fn verify_image(
+    image: &Image,
+    mode: VerifyMode,
     bininfo: &BinInfoResponse,
-    d: &HidDevice,
-) -> Result<bool, UtilError> {
-    // get checksums of existing pages
+    d: &impl ReadWrite,
+    observer: &impl Observer,
+) -> Result<VerifyReport, UtilError> {
+    let pages = Pages::new(image, bininfo.flash_page_size);
+
+    observer.phase(Phase::Verify {
+        pages: pages.data.len(),
+    });
+    Ok(VerifyReport {
+        pages: pages.data.len(),
+        mismatches: verify(&pages, mode, bininfo, d, observer)?,
+    })
+}
+
+fn crc(page: &[u8]) -> u16 {
+    let mut xmodem = CRCu16::crc16xmodem();
+    xmodem.digest(page);
+    xmodem.get_crc()
+}
+
+fn mismatched_addresses(mismatches: Vec<Mismatch>) -> Vec<u32> {
+    mismatches
+        .into_iter()
+        .map(|mismatch| mismatch.target_address)
+        .collect()
+}
 
-    let top_address = address + binary.len() as u32;
+/// Returns every page that differs on the device, checked the way mode says.
+fn verify(
+    pages: &Pages,
+    mode: VerifyMode,
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+    observer: &impl Observer,
+) -> Result<Vec<Mismatch>, UtilError> {
+    match mode {
+        VerifyMode::Crc => verify_crc(pages, bininfo, d, observer),
+        VerifyMode::Readback => verify_readback(pages, bininfo, d, observer),
+        VerifyMode::None => Ok(vec![]),
+    }
+}
 
+/// Verifys checksum of pages.
+fn verify_crc(
+    pages: &Pages,
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+    observer: &impl Observer,
+) -> Result<Vec<Mismatch>, UtilError> {
+    // get checksums of existing pages, a single command covers a run of adjacent pages
     let max_pages = bininfo.max_message_size / 2 - 2;
-    let steps = max_pages * bininfo.flash_page_size;
     let mut device_checksums = vec![];
 
-    for target_address in (address..top_address).step_by(steps as usize) {
-        // add divisor-1 to dividend to round up
-        let pages_left = (top_address - target_address + (bininfo.flash_page_size - 1))
-            / bininfo.flash_page_size;
-
-        let num_pages = if pages_left < max_pages {
-            pages_left
-        } else {
-            max_pages
-        };
+    for run in runs(pages.data.keys().copied(), bininfo.flash_page_size) {
+        for batch in run.chunks(max_pages as usize) {
+            let num_pages = batch.len() as u32;
 
-        let chk = checksum_pages(d, target_address, num_pages).map_err(UtilError::from)?;
-        device_checksums.extend_from_slice(&chk.checksums);
+            let chk = retry(observer, || {
+                let chk = checksum_pages(d, batch[0], num_pages)?;
+                if chk.checksums.len() != num_pages as usize {
+                    return Err(Error::Parse);
+                }
+                Ok(chk)
+            })?;
+            device_checksums.extend_from_slice(&chk.checksums);
+            observer.compared(batch.len());
+        }
     }
 
-    let mut binary_checksums = vec![];
-
     //collect and sums so we can view all mismatches, not just first
-    for page in binary.chunks(bininfo.flash_page_size as usize) {
-        let mut xmodem = CRCu16::crc16xmodem();
-        xmodem.digest(&page);
+    let binary_checksums = pages.data.values().map(|page| crc(page));
 
-        binary_checksums.push(xmodem.get_crc());
+    Ok(pages
+        .data
+        .keys()
+        .zip(binary_checksums.zip(device_checksums))
+        .filter(|(_, (expected, actual))| expected != actual)
+        .map(|(target_address, (expected, actual))| Mismatch {
+            target_address: *target_address,
+            expected,
+            actual,
+            // the same page padded the way erased flash reads tells padding from data
+            padding: crc(&pages.erased[target_address]) == actual,
+            first_difference: None,
+        })
+        .collect())
+}
+
+/// Reads pages back and compares them byte for byte.
+fn verify_readback(
+    pages: &Pages,
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+    observer: &impl Observer,
+) -> Result<Vec<Mismatch>, UtilError> {
+    let mut mismatches = vec![];
+
+    for (target_address, page) in &pages.data {
+        let actual = retry(observer, || {
+            read_memory(d, bininfo, *target_address, page.len())
+        })?;
+        observer.compared(1);
+
+        let differences: Vec<usize> = (0..page.len()).filter(|&i| page[i] != actual[i]).collect();
+        if let Some(first) = differences.first() {
+            // padding is wherever zero and erased padded pages disagree
+            let erased = &pages.erased[target_address];
+            mismatches.push(Mismatch {
+                target_address: *target_address,
+                expected: crc(page),
+                actual: crc(&actual),
+                padding: differences.iter().all(|&i| page[i] != erased[i]),
+                first_difference: Some(target_address + *first as u32),
+            });
+        }
+    }
+    Ok(mismatches)
+}
+
+/// Read flash from address, the start of the application past the bootloader, to the end of the flash_num_pages * flash_page_size bytes the device has, a page at a time.
+pub fn dump(
+    address: u32,
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+    observer: &impl Observer,
+) -> Result<Image, UtilError> {
+    let page_size = bininfo.flash_page_size as usize;
+    let flash_size = bininfo.flash_num_pages as u64 * page_size as u64;
+    if address as u64 >= flash_size {
+        return Err(UtilError::InvalidBinary);
     }
+    let len = (flash_size - address as u64) as usize;
+    observer.phase(Phase::Read { bytes: len });
+
+    let mut data = Vec::with_capacity(len);
+    while data.len() < len {
+        let target_address = address + data.len() as u32;
+        let count = page_size.min(len - data.len());
+        data.extend(retry(observer, || {
+            read_memory(d, bininfo, target_address, count)
+        })?);
+        observer.read(target_address, count);
+    }
+
+    Image::from_bin(&data, address)
+}
 
-    Ok(binary_checksums.eq(&device_checksums))
+/// Group sorted page addresses into runs of adjacent pages.
+fn runs(addresses: impl Iterator<Item = u32>, page_size: u32) -> Vec<Vec<u32>> {
+    let mut runs: Vec<Vec<u32>> = vec![];
+    for address in addresses {
+        match runs.last_mut() {
+            Some(run) if run.last().map(|last| last + page_size) == Some(address) => {
+                run.push(address)
+            }
+            _ => runs.push(vec![address]),
+        }
+    }
+    runs
 }
 
 pub fn vendor_map() -> std::collections::HashMap<u16, Vec<u16>> {
@@ -255,4 +786,362 @@ mod tests {
         assert_eq!(data[12], 2);
         assert_eq!(data[20], 3);
     }
+
+    #[cfg(feature = "emulator")]
+    #[test]
+    fn flash_uf2_sparse_out_of_order() {
+        use super::uf2;
+        use crate::emulator::Emulator;
+        use crate::FamilyId;
+
+        let low: Vec<u8> = (0..700).map(|i| i as u8).collect();
+        let high = vec![0xAB; 100];
+
+        let mut blocks = uf2::from_bin(&low, 0x4000, 256, Some(FamilyId::ATSAMD51)).unwrap();
+        blocks.extend(uf2::from_bin(&high, 0x8100, 256, Some(FamilyId::ATSAMD51)).unwrap());
+        blocks.reverse();
+
+        let emulator = Emulator::default();
+        let bininfo = emulator.bininfo();
+
+        super::flash_uf2(&blocks, super::VerifyMode::Crc, &bininfo, &emulator, &()).unwrap();
+
+        let flash = emulator.flash();
+        assert_eq!(&flash[0x4000..][..700], low.as_slice());
+        assert_eq!(&flash[0x8100..][..100], high.as_slice());
+        // untouched between the two
+        assert_eq!(flash[0x6000], 0xFF);
+
+        let other = Emulator::new(512, 1024, 1024, FamilyId::NRF52840);
+        assert!(matches!(
+            super::flash_uf2(
+                &blocks,
+                super::VerifyMode::Crc,
+                &other.bininfo(),
+                &other,
+                &()
+            ),
+            Err(super::UtilError::FamilyMismatch { .. })
+        ));
+    }
+
+    #[cfg(feature = "emulator")]
+    #[test]
+    fn flash_and_verify_emulated() {
+        use crate::emulator::Emulator;
+
+        let (binary, address) = super::elf_to_bin(
+            [
+                env!("CARGO_MANIFEST_DIR"),
+                "src/utils/testdata/blinky_1.47.0",
+            ]
+            .iter()
+            .collect(),
+        )
+        .unwrap();
+
+        let emulator = Emulator::default();
+        let bininfo = crate::bin_info(&emulator).unwrap();
+
+        super::flash_bin(
+            &binary,
+            address,
+            super::VerifyMode::Crc,
+            &bininfo,
+            &emulator,
+        )
+        .unwrap();
+        assert_eq!(emulator.mode(), crate::BinInfoMode::User);
+        assert_eq!(
+            &emulator.flash()[address as usize..][..binary.len()],
+            binary.as_slice()
+        );
+
+        super::verify_bin(
+            &binary,
+            address,
+            super::VerifyMode::Crc,
+            &bininfo,
+            &emulator,
+        )
+        .unwrap();
+
+        let mut different = binary.clone();
+        different[600] ^= 0xFF;
+        assert!(matches!(
+            super::verify_bin(
+                &different,
+                address,
+                super::VerifyMode::Crc,
+                &bininfo,
+                &emulator
+            ),
+            Err(super::UtilError::ContentsDifferent)
+        ));
+    }
+
+    #[cfg(feature = "emulator")]
+    #[test]
+    fn verify_reports_mismatches() {
+        use crate::emulator::Emulator;
+
+        let emulator = Emulator::default();
+        let image = super::Image::from_bin(&[0x55; 1000], 0x4000).unwrap();
+        super::flash_image(
+            &image,
+            super::VerifyMode::Crc,
+            &emulator.bininfo(),
+            &emulator,
+            &(),
+        )
+        .unwrap();
+
+        let report = super::verify_image(
+            &image,
+            super::VerifyMode::Crc,
+            &emulator.bininfo(),
+            &emulator,
+            &(),
+        )
+        .unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.pages, 2);
+
+        // data differs in the first page, only the padding past the image in the second
+        let mut flash = emulator.flash();
+        flash[0x4010] = 0;
+        flash[0x4000 + 1000..0x4400].fill(0xFF);
+        emulator.set_flash(0, &flash);
+
+        let report = super::verify_image(
+            &image,
+            super::VerifyMode::Crc,
+            &emulator.bininfo(),
+            &emulator,
+            &(),
+        )
+        .unwrap();
+        assert_eq!(
+            report
+                .mismatches
+                .iter()
+                .map(|mismatch| (mismatch.target_address, mismatch.padding))
+                .collect::<Vec<_>>(),
+            vec![(0x4000, false), (0x4200, true)]
+        );
+        assert_ne!(report.mismatches[0].expected, report.mismatches[0].actual);
+        assert!(!report.data_matches());
+
+        // a small max_message_size makes readback take several READ WORDS per page
+        let emulator = Emulator::new(512, 1024, 64, crate::FamilyId::ATSAMD51);
+        emulator.set_flash(0, &flash[..0x8000]);
+        let readback = super::verify_image(
+            &image,
+            super::VerifyMode::Readback,
+            &emulator.bininfo(),
+            &emulator,
+            &(),
+        )
+        .unwrap();
+        assert_eq!(
+            readback
+                .mismatches
+                .iter()
+                .map(|mismatch| (mismatch.padding, mismatch.first_difference))
+                .collect::<Vec<_>>(),
+            vec![(false, Some(0x4010)), (true, Some(0x4000 + 1000))]
+        );
+        assert_eq!(readback.mismatches[0].actual, report.mismatches[0].actual);
+    }
+
+    #[test]
+    fn load_with_and_without_address() {
+        use super::{Image, UtilError};
+
+        let image = Image::from_bin(&[1, 2, 3, 4], 0x4000).unwrap();
+        let directory = std::env::temp_dir();
+        let hex = directory.join(format!("hf2_load_{}.hex", std::process::id()));
+        let unknown = directory.join(format!("hf2_load_{}.img", std::process::id()));
+        super::ihex::write(hex.clone(), &image).unwrap();
+        std::fs::copy(&hex, &unknown).unwrap();
+
+        assert_eq!(super::load(hex.clone(), None).unwrap(), image);
+        assert!(matches!(
+            super::load(hex.clone(), Some(0x4000)),
+            Err(UtilError::UnexpectedAddress)
+        ));
+        // sniffed without an address, raw with one
+        assert_eq!(super::load(unknown.clone(), None).unwrap(), image);
+        let raw = super::load(unknown.clone(), Some(0x8000)).unwrap();
+        assert_eq!(raw.address(), Some(0x8000));
+        assert_eq!(raw.segments()[0].data, std::fs::read(&unknown).unwrap());
+
+        std::fs::remove_file(hex).unwrap();
+        std::fs::remove_file(unknown).unwrap();
+    }
+
+    #[cfg(feature = "emulator")]
+    #[test]
+    fn dump_and_restore() {
+        use crate::emulator::Emulator;
+
+        let source = Emulator::new(256, 64, 1024, crate::FamilyId::ATSAMD21);
+        // a bootloader ahead of the app at 0x800
+        source.set_flash(0, &[0xB0; 0x800]);
+        let binary: Vec<u8> = (0..3000).map(|i| (i * 7) as u8).collect();
+        source.set_flash(0x800, &binary);
+
+        let image = super::dump(0x800, &source.bininfo(), &source, &()).unwrap();
+        assert_eq!(image.len(), 256 * 64 - 0x800);
+        assert_eq!(
+            image.to_bin().unwrap(),
+            (source.flash()[0x800..].to_vec(), 0x800)
+        );
+
+        let target = Emulator::new(256, 64, 1024, crate::FamilyId::ATSAMD21);
+        super::flash_image(
+            &image,
+            super::VerifyMode::Crc,
+            &target.bininfo(),
+            &target,
+            &(),
+        )
+        .unwrap();
+        assert_eq!(target.flash()[0x800..], source.flash()[0x800..]);
+        assert!(target.flash()[..0x800].iter().all(|byte| *byte == 0xFF));
+
+        assert!(matches!(
+            super::dump(256 * 64, &source.bininfo(), &source, &()),
+            Err(super::UtilError::InvalidBinary)
+        ));
+
+        // too small a message for even one word, rather than underflowing
+        let tiny = crate::BinInfoResponse {
+            max_message_size: 3,
+            ..source.bininfo()
+        };
+        assert!(matches!(
+            super::dump(0x800, &tiny, &source, &()),
+            Err(super::UtilError::Communication(crate::Error::Arguments))
+        ));
+    }
+
+    #[cfg(feature = "emulator")]
+    #[test]
+    fn flash_without_verify_writes_everything() {
+        use crate::emulator::Emulator;
+
+        let emulator = Emulator::default();
+        let binary = [0x42; 1024];
+        for _ in 0..2 {
+            let report = super::flash_bin(
+                &binary,
+                0x4000,
+                super::VerifyMode::None,
+                &emulator.bininfo(),
+                &emulator,
+            )
+            .unwrap();
+            assert_eq!((report.skipped, report.written), (0, 2));
+        }
+
+        let report = super::flash_bin(
+            &binary,
+            0x4000,
+            super::VerifyMode::Readback,
+            &emulator.bininfo(),
+            &emulator,
+        )
+        .unwrap();
+        assert_eq!((report.skipped, report.written), (2, 0));
+    }
+
+    #[cfg(feature = "emulator")]
+    #[test]
+    fn flash_skips_unchanged_pages() {
+        use crate::emulator::Emulator;
+
+        let binary: Vec<u8> = (0..4096).map(|i| (i / 7) as u8).collect();
+        let emulator = Emulator::default();
+
+        let report = super::flash_bin(
+            &binary,
+            0x4000,
+            super::VerifyMode::Crc,
+            &emulator.bininfo(),
+            &emulator,
+        )
+        .unwrap();
+        assert_eq!((report.pages, report.skipped, report.written), (8, 0, 8));
+
+        let mut changed = binary.clone();
+        changed[1500] ^= 0xFF;
+        let report = super::flash_bin(
+            &changed,
+            0x4000,
+            super::VerifyMode::Crc,
+            &emulator.bininfo(),
+            &emulator,
+        )
+        .unwrap();
+        assert_eq!((report.pages, report.skipped, report.written), (8, 7, 1));
+        assert_eq!(&emulator.flash()[0x4000..][..4096], changed.as_slice());
+    }
+
+    #[cfg(feature = "emulator")]
+    #[test]
+    fn observer_sees_every_phase() {
+        use super::{Observer, Phase};
+        use crate::emulator::Emulator;
+        use core::cell::RefCell;
+
+        #[derive(Default)]
+        struct Recorder {
+            phases: RefCell<Vec<Phase>>,
+            written: RefCell<usize>,
+            compared: RefCell<usize>,
+        }
+
+        impl Observer for Recorder {
+            fn phase(&self, phase: Phase) {
+                self.phases.borrow_mut().push(phase);
+            }
+            fn written(&self, _target_address: u32, bytes: usize) {
+                *self.written.borrow_mut() += bytes;
+            }
+            fn compared(&self, pages: usize) {
+                *self.compared.borrow_mut() += pages;
+            }
+        }
+
+        let emulator = Emulator::default();
+        emulator.set_mode(crate::BinInfoMode::User);
+        let recorder = Recorder::default();
+
+        let image = super::Image::from_bin(&[1; 1000], 0x4000).unwrap();
+        super::flash_image(
+            &image,
+            super::VerifyMode::Crc,
+            &emulator.bininfo(),
+            &emulator,
+            &recorder,
+        )
+        .unwrap();
+
+        assert_eq!(
+            *recorder.phases.borrow(),
+            vec![
+                Phase::StartFlash,
+                Phase::Compare { pages: 2 },
+                Phase::Write {
+                    pages: 2,
+                    bytes: 1024
+                },
+                Phase::Verify { pages: 2 },
+                Phase::Reset
+            ]
+        );
+        assert_eq!(*recorder.written.borrow(), 1024);
+        assert_eq!(*recorder.compared.borrow(), 4);
+    }
 }
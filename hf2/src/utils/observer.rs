@@ -0,0 +1,41 @@
+use crate::Error;
+
+/// Step of a flash or verify, carrying how much work it covers.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Phase {
+    /// Handing over from the app to the bootloader.
+    StartFlash,
+    /// Checksumming or reading back pages to find the ones the device already holds.
+    Compare { pages: usize },
+    /// Writing pages, again for every round of rewriting pages that didn't verify.
+    Write { pages: usize, bytes: usize },
+    /// Checksumming or reading back pages after writing, or on their own for a verify.
+    Verify { pages: usize },
+    /// Resetting into the app.
+    Reset,
+    /// Reading flash back for a dump.
+    Read { bytes: usize },
+}
+
+/// Events while flashing or verifying, every method defaults to doing nothing. Takes &self like ReadWrite, so use interior mutability or a channel to keep state.
+pub trait Observer {
+    fn phase(&self, _phase: Phase) {}
+
+    /// A page was written.
+    fn written(&self, _target_address: u32, _bytes: usize) {}
+
+    /// A chunk of flash was read for a dump.
+    fn read(&self, _address: u32, _bytes: usize) {}
+
+    /// A batch of pages was checksummed or read back during Compare or Verify.
+    fn compared(&self, _pages: usize) {}
+
+    /// Pages already matching the device, which won't be written.
+    fn skipped(&self, _pages: usize) {}
+
+    /// A command failed and is about to be tried again.
+    fn retry(&self, _attempt: usize, _error: &Error) {}
+}
+
+/// Ignores every event.
+impl Observer for () {}
@@ -0,0 +1,103 @@
+use super::{Observer, Phase};
+use crate::Error;
+use colored::Colorize;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+/// How Progress words its bars and the notes it prints around them.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Labels {
+    /// Lowercase labels, notes and warnings on stderr.
+    Plain,
+    /// Green capitalized labels right aligned like cargo's own output, notes on stdout.
+    Cargo,
+}
+
+/// Renders flash, verify and dump phases as a progress bar on stderr with throughput and ETA.
+pub struct Progress {
+    bar: ProgressBar,
+    labels: Labels,
+}
+
+impl Progress {
+    pub fn new(labels: Labels) -> Self {
+        Self {
+            bar: ProgressBar::hidden(),
+            labels,
+        }
+    }
+
+    /// Clear whatever bar is showing, for once the operation returned.
+    pub fn finish(&self) {
+        self.bar.finish_and_clear();
+    }
+
+    /// Start a fresh bar for a phase, counting bytes or pages.
+    fn start(&self, label: &'static str, len: usize, bytes: bool) {
+        let (message, width, bar) = match self.labels {
+            Labels::Plain => (label.to_lowercase(), 10, 40),
+            Labels::Cargo => (label.green().bold().to_string(), 12, 27),
+        };
+        let counts = if bytes {
+            "{bytes}/{total_bytes} {bytes_per_sec} eta {eta}"
+        } else {
+            "{pos}/{len} pages {per_sec} eta {eta}"
+        };
+
+        self.bar.finish_and_clear();
+        self.bar.set_draw_target(ProgressDrawTarget::stderr());
+        self.bar.reset();
+        self.bar.set_length(len as u64);
+        self.bar.set_style(
+            ProgressStyle::default_bar()
+                .template(&format!("{{msg:>{}}} [{{bar:{}}}] {}", width, bar, counts))
+                .expect("valid template")
+                .progress_chars("=> "),
+        );
+        self.bar.set_message(message);
+    }
+}
+
+impl Observer for Progress {
+    fn phase(&self, phase: Phase) {
+        match phase {
+            Phase::StartFlash => self.bar.suspend(|| match self.labels {
+                Labels::Plain => eprintln!("starting flash"),
+                Labels::Cargo => println!("    {} flash", "Starting".green().bold()),
+            }),
+            Phase::Compare { pages } => self.start("Comparing", pages, false),
+            Phase::Write { bytes, .. } => self.start("Writing", bytes, true),
+            Phase::Verify { pages } => self.start("Verifying", pages, false),
+            Phase::Reset => self.finish(),
+            Phase::Read { bytes } => self.start("Reading", bytes, true),
+        }
+    }
+
+    fn written(&self, _target_address: u32, bytes: usize) {
+        self.bar.inc(bytes as u64);
+    }
+
+    fn read(&self, _address: u32, bytes: usize) {
+        self.bar.inc(bytes as u64);
+    }
+
+    fn compared(&self, pages: usize) {
+        self.bar.inc(pages as u64);
+    }
+
+    fn retry(&self, attempt: usize, error: &Error) {
+        self.bar.suspend(|| match self.labels {
+            Labels::Plain => eprintln!(
+                "{} attempt {} failed: {}, retrying",
+                "warning:".yellow().bold(),
+                attempt,
+                error
+            ),
+            Labels::Cargo => println!(
+                "    {} attempt {} failed: {}",
+                "Retrying".yellow().bold(),
+                attempt,
+                error
+            ),
+        });
+    }
+}
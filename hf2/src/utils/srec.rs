@@ -0,0 +1,150 @@
+use super::{decode_hex, Image, UtilError};
+use std::path::PathBuf;
+use std::{fs::File, io::Read};
+
+/// Data records of a Motorola S-record file, each at its absolute address, in file order.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Srec {
+    /// Contents of the S0 record, usually a module name.
+    pub header: Option<Vec<u8>>,
+    pub records: Vec<(u32, Vec<u8>)>,
+    /// From the S7, S8 or S9 termination record.
+    pub start_address: Option<u32>,
+}
+
+impl Srec {
+    /// Sparse image of the records, which may come in any order but must agree where they overlap.
+    pub fn to_image(&self) -> Result<Image, UtilError> {
+        let mut image = Image::new();
+        for (address, data) in &self.records {
+            image.add(*address, data)?;
+        }
+        image.start_address = self.start_address;
+        Ok(image)
+    }
+}
+
+/// Parse the text of an S-record file, checking every record's checksum.
+pub fn parse(text: &str) -> Result<Srec, UtilError> {
+    let mut srec = Srec::default();
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |reason| UtilError::InvalidSrec {
+            line: index + 1,
+            reason,
+        };
+
+        let mut chars = line.chars();
+        if chars.next() != Some('S') {
+            return Err(error("record doesn't start with 'S'"));
+        }
+        let kind = chars
+            .next()
+            .and_then(|kind| kind.to_digit(10))
+            .ok_or_else(|| error("invalid record type"))?;
+
+        // byte count covers address, data and checksum
+        let bytes = decode_hex(&line[2..]).ok_or_else(|| error("invalid hex digits"))?;
+        if bytes.len() < 2 || bytes.len() != 1 + bytes[0] as usize {
+            return Err(error("record length doesn't match its byte count"));
+        }
+        if bytes.iter().fold(0_u8, |sum, b| sum.wrapping_add(*b)) != 0xFF {
+            return Err(error("checksum mismatch"));
+        }
+
+        let address_size = match kind {
+            0 | 1 | 5 | 9 => 2,
+            2 | 6 | 8 => 3,
+            3 | 7 => 4,
+            _ => return Err(error("invalid record type")),
+        };
+        let body = &bytes[1..bytes.len() - 1];
+        if body.len() < address_size {
+            return Err(error("record too short for its address"));
+        }
+        let address = body[..address_size]
+            .iter()
+            .fold(0_u32, |address, b| address << 8 | *b as u32);
+        let data = &body[address_size..];
+
+        match kind {
+            0 => srec.header = Some(data.to_vec()),
+            1..=3 => srec.records.push((address, data.to_vec())),
+            // record counts, nothing to check them against that the checksums didn't already
+            5 | 6 => (),
+            _ => srec.start_address = Some(address),
+        }
+    }
+
+    if srec.records.is_empty() {
+        return Err(UtilError::InvalidSrec {
+            line: text.lines().count(),
+            reason: "no data records",
+        });
+    }
+    Ok(srec)
+}
+
+/// Read and parse an S-record file.
+pub fn read(path: PathBuf) -> Result<Srec, UtilError> {
+    let mut file = File::open(path).map_err(UtilError::File)?;
+    let mut buffer = String::new();
+    file.read_to_string(&mut buffer).map_err(UtilError::File)?;
+
+    parse(&buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn all_address_sizes() {
+        let srec = parse(
+            "S00600004844521B\n\
+             S107001001020304DE\n\
+             S20600100055AAEA\n\
+             S307080000001122BD\n\
+             S5030003F9\n\
+             S70508000101F0\n",
+        )
+        .unwrap();
+
+        assert_eq!(srec.header, Some(b"HDR".to_vec()));
+        assert_eq!(
+            srec.records,
+            vec![
+                (0x0010, vec![1, 2, 3, 4]),
+                (0x1000, vec![0x55, 0xAA]),
+                (0x0800_0000, vec![0x11, 0x22])
+            ]
+        );
+        assert_eq!(srec.start_address, Some(0x0800_0101));
+
+        let image = parse("S107001001020304DE\nS9030000FC\n")
+            .unwrap()
+            .to_image()
+            .unwrap();
+        assert_eq!(image.to_bin().unwrap(), (vec![1, 2, 3, 4], 0x10));
+        assert_eq!(image.start_address, Some(0));
+    }
+
+    #[test]
+    fn bad_records() {
+        let line = |text| match parse(text) {
+            Err(UtilError::InvalidSrec { line, .. }) => line,
+            other => panic!("{:?}", other),
+        };
+
+        // checksum off by one
+        assert_eq!(line("S107001001020304DF\n"), 1);
+        // byte count says 7 but carries 6
+        assert_eq!(line("S10700100102039D\n"), 1);
+        assert_eq!(line("S107001001020304DE\nS4030000FC\n"), 2);
+        assert_eq!(line("S9030000FC\n"), 1);
+    }
+}
@@ -0,0 +1,803 @@
+use super::UtilError;
+use crate::{read_memory, write_memory, BinInfoResponse, FamilyId, ReadWrite};
+use roxmltree::Node;
+use std::path::PathBuf;
+use std::{fs::File, io::Read};
+
+/// Peripherals of a CMSIS-SVD file, with clusters and arrays flattened into plain registers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Device {
+    pub name: String,
+    pub peripherals: Vec<Peripheral>,
+}
+
+impl Device {
+    /// Peripheral by name, ignoring case.
+    pub fn peripheral(&self, name: &str) -> Option<&Peripheral> {
+        self.peripherals
+            .iter()
+            .find(|peripheral| peripheral.name.eq_ignore_ascii_case(name))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Peripheral {
+    pub name: String,
+    pub description: Option<String>,
+    pub base_address: u32,
+    pub registers: Vec<Register>,
+}
+
+impl Peripheral {
+    /// Register by name, ignoring case, like GROUP[0].DIR for one inside a cluster.
+    pub fn register(&self, name: &str) -> Option<&Register> {
+        self.registers
+            .iter()
+            .find(|register| register.name.eq_ignore_ascii_case(name))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Register {
+    /// Cluster names come first, separated by dots.
+    pub name: String,
+    pub description: Option<String>,
+    /// Absolute, the peripheral's base address already added.
+    pub address: u32,
+    /// Bits, 8, 16 or 32.
+    pub size: u32,
+    /// read-write, read-only, write-only, writeOnce or read-writeOnce
+    pub access: Option<String>,
+    /// Reading has a side effect, like clearing a flag.
+    pub read_action: bool,
+    pub reset_value: Option<u64>,
+    pub fields: Vec<Field>,
+    /// HF2 only reads and writes whole words, so a register narrower than a word or off a word boundary is reached through the words around it. That's only safe when every other register in those words is plain read-write without read side effects.
+    pub word_safe: bool,
+}
+
+impl Register {
+    /// Reading doesn't fail or change anything.
+    pub fn readable(&self) -> bool {
+        self.access.as_deref() != Some("write-only") && !self.read_action
+    }
+
+    pub fn writable(&self) -> bool {
+        self.access.as_deref() != Some("read-only")
+    }
+
+    /// Field by name, ignoring case.
+    pub fn field(&self, name: &str) -> Option<&Field> {
+        self.fields
+            .iter()
+            .find(|field| field.name.eq_ignore_ascii_case(name))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub name: String,
+    pub description: Option<String>,
+    pub bit_offset: u32,
+    pub bit_width: u32,
+    /// What writing does, like oneToClear for flags cleared by writing 1, None for a plain write. Given on the field or inherited from its register.
+    pub modified_write_values: Option<String>,
+    pub values: Vec<EnumeratedValue>,
+}
+
+impl Field {
+    /// This field's bits out of a register value.
+    pub fn extract(&self, register: u64) -> u64 {
+        (register >> self.bit_offset) & self.mask()
+    }
+
+    /// A register value with this field replaced, value must fit the field's width.
+    pub fn insert(&self, register: u64, value: u64) -> Result<u64, UtilError> {
+        if value & !self.mask() != 0 {
+            return Err(UtilError::InvalidFieldValue {
+                field: self.name.clone(),
+                value: value.to_string(),
+            });
+        }
+        Ok(register & !(self.mask() << self.bit_offset) | value << self.bit_offset)
+    }
+
+    /// Enumerated value matching a field value.
+    pub fn value_of(&self, value: u64) -> Option<&EnumeratedValue> {
+        self.values
+            .iter()
+            .find(|enumerated| enumerated.value == value)
+    }
+
+    /// A value by enumerated name, ignoring case, or as a number.
+    pub fn parse_value(&self, value: &str) -> Result<u64, UtilError> {
+        self.values
+            .iter()
+            .find(|enumerated| enumerated.name.eq_ignore_ascii_case(value))
+            .map(|enumerated| enumerated.value)
+            .or_else(|| parse_number(value))
+            .ok_or_else(|| UtilError::InvalidFieldValue {
+                field: self.name.clone(),
+                value: value.to_string(),
+            })
+    }
+
+    /// Value that leaves the field as it is when written, for fields where writing back what was read would change it.
+    pub fn neutral(&self) -> Option<u64> {
+        match self.modified_write_values.as_deref()? {
+            "oneToClear" | "oneToSet" | "oneToToggle" => Some(0),
+            "zeroToClear" | "zeroToSet" | "zeroToToggle" => Some(self.mask()),
+            _ => None,
+        }
+    }
+
+    fn mask(&self) -> u64 {
+        if self.bit_width >= 64 {
+            u64::MAX
+        } else {
+            (1 << self.bit_width) - 1
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnumeratedValue {
+    pub name: String,
+    pub description: Option<String>,
+    pub value: u64,
+}
+
+/// Read a register, which must be readable.
+pub fn read_register(
+    register: &Register,
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+) -> Result<u64, UtilError> {
+    shares_word_safely(register)?;
+    let bytes = read_memory(d, bininfo, register.address, (register.size / 8) as usize)?;
+    let mut value = [0_u8; 8];
+    value[..bytes.len()].copy_from_slice(&bytes);
+    Ok(u64::from_le_bytes(value))
+}
+
+/// Write a whole register.
+pub fn write_register(
+    register: &Register,
+    value: u64,
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+) -> Result<(), UtilError> {
+    shares_word_safely(register)?;
+    let bytes = value.to_le_bytes();
+    write_memory(
+        d,
+        bininfo,
+        register.address,
+        &bytes[..(register.size / 8) as usize],
+    )?;
+    Ok(())
+}
+
+/// Set fields of a register to values given as numbers or enumerated names, leaving the rest. Write only registers start from zero rather than being read, and fields like write 1 to clear flags are written with the value that leaves them alone. Returns the value written.
+pub fn write_fields(
+    register: &Register,
+    fields: &[(&str, &str)],
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+) -> Result<u64, UtilError> {
+    if !register.writable() {
+        return Err(UtilError::InvalidSvd {
+            element: register.name.clone(),
+            reason: "register is read only",
+        });
+    }
+
+    let mut value = if register.readable() {
+        read_register(register, bininfo, d)?
+    } else {
+        0
+    };
+    for field in &register.fields {
+        if let Some(neutral) = field.neutral() {
+            value = field.insert(value, neutral)?;
+        }
+    }
+    for (name, field_value) in fields {
+        let field = register.field(name).ok_or_else(|| UtilError::InvalidSvd {
+            element: format!("{}.{}", register.name, name),
+            reason: "no such field",
+        })?;
+        value = field.insert(value, field.parse_value(field_value)?)?;
+    }
+
+    write_register(register, value, bininfo, d)?;
+    Ok(value)
+}
+
+fn shares_word_safely(register: &Register) -> Result<(), UtilError> {
+    if register.word_safe {
+        Ok(())
+    } else {
+        Err(invalid(
+            &register.name,
+            "shares a word with registers that can't be read and written back",
+        ))
+    }
+}
+
+/// Where to find an SVD file for a family, the vendor's device family pack usually carries one.
+pub fn suggest(family: FamilyId) -> Option<&'static str> {
+    match family {
+        FamilyId::ATSAMD21 => {
+            Some("ATSAMD21*.svd from Microchip's SAMD21_DFP, or the atsamd21 pac crates")
+        }
+        FamilyId::ATSAMD51 => {
+            Some("ATSAMD51*.svd from Microchip's SAMD51_DFP, or the atsamd51 pac crates")
+        }
+        FamilyId::NRF52840 => Some("nrf52840.svd from Nordic's nRF MDK"),
+        FamilyId::STM32F103 => {
+            Some("STM32F103xx.svd from ST's STM32F1 support pack, or the stm32-rs repository")
+        }
+        FamilyId::STM32F401 => {
+            Some("STM32F401.svd from ST's STM32F4 support pack, or the stm32-rs repository")
+        }
+        _ => None,
+    }
+}
+
+/// Sizes and access given on the device, a peripheral or a cluster, which registers inherit.
+#[derive(Clone)]
+struct Defaults {
+    size: u32,
+    access: Option<String>,
+    reset_value: Option<u64>,
+}
+
+impl Defaults {
+    fn inherit(&self, node: Node) -> Result<Self, UtilError> {
+        Ok(Self {
+            size: number(node, "size")?.map_or(self.size, |size| size as u32),
+            access: text(node, "access")
+                .map(str::to_string)
+                .or_else(|| self.access.clone()),
+            reset_value: number(node, "resetValue")?.or(self.reset_value),
+        })
+    }
+}
+
+/// Parse the text of an SVD file.
+pub fn parse(svd: &str) -> Result<Device, UtilError> {
+    let document = roxmltree::Document::parse(svd).map_err(UtilError::Xml)?;
+    let root = document.root_element();
+    if !root.has_tag_name("device") {
+        return Err(invalid(root.tag_name().name(), "expected a device"));
+    }
+
+    let defaults = Defaults {
+        size: 32,
+        access: None,
+        reset_value: None,
+    }
+    .inherit(root)?;
+
+    let nodes: Vec<_> = child(root, "peripherals")
+        .map(|peripherals| elements(peripherals, "peripheral").collect())
+        .unwrap_or_default();
+
+    // derived peripherals may come before the one they're derived from
+    let mut peripherals: Vec<Option<Peripheral>> = vec![None; nodes.len()];
+    for (i, node) in nodes.iter().enumerate() {
+        if node.attribute("derivedFrom").is_none() {
+            peripherals[i] = Some(peripheral(*node, None, &defaults)?);
+        }
+    }
+    for (i, node) in nodes.iter().enumerate() {
+        if let Some(base) = node.attribute("derivedFrom") {
+            let base = peripherals
+                .iter()
+                .flatten()
+                .find(|peripheral| peripheral.name == base)
+                .cloned()
+                .ok_or_else(|| invalid(base, "derived from an unknown peripheral"))?;
+            peripherals[i] = Some(peripheral(*node, Some(&base), &defaults)?);
+        }
+    }
+
+    Ok(Device {
+        name: text(root, "name").unwrap_or_default().to_string(),
+        peripherals: peripherals.into_iter().flatten().collect(),
+    })
+}
+
+/// Read and parse an SVD file.
+pub fn read(path: PathBuf) -> Result<Device, UtilError> {
+    let mut file = File::open(path).map_err(UtilError::File)?;
+    let mut buffer = String::new();
+    file.read_to_string(&mut buffer).map_err(UtilError::File)?;
+
+    parse(&buffer)
+}
+
+fn peripheral(
+    node: Node,
+    base: Option<&Peripheral>,
+    defaults: &Defaults,
+) -> Result<Peripheral, UtilError> {
+    let name = text(node, "name").ok_or_else(|| invalid("peripheral", "missing name"))?;
+    let base_address = number(node, "baseAddress")?
+        .map(|address| address as u32)
+        .or_else(|| base.map(|base| base.base_address))
+        .ok_or_else(|| invalid(name, "missing baseAddress"))?;
+
+    let registers = match (child(node, "registers"), base) {
+        (Some(registers_node), _) => {
+            let mut registers = vec![];
+            collect(
+                registers_node,
+                base_address,
+                "",
+                &defaults.inherit(node)?,
+                &mut registers,
+            )?;
+            word_safety(&mut registers);
+            registers
+        }
+        // same registers at another address
+        (None, Some(base)) => base
+            .registers
+            .iter()
+            .cloned()
+            .map(|mut register| {
+                register.address = register.address - base.base_address + base_address;
+                register
+            })
+            .collect(),
+        (None, None) => vec![],
+    };
+
+    Ok(Peripheral {
+        name: name.to_string(),
+        description: text(node, "description")
+            .map(str::to_string)
+            .or_else(|| base.and_then(|base| base.description.clone())),
+        base_address,
+        registers,
+    })
+}
+
+/// Flatten the registers and clusters under node, at address, into registers.
+fn collect(
+    node: Node,
+    address: u32,
+    prefix: &str,
+    defaults: &Defaults,
+    registers: &mut Vec<Register>,
+) -> Result<(), UtilError> {
+    for element in node.children().filter(|child| child.is_element()) {
+        let is_register = element.has_tag_name("register");
+        if !is_register && !element.has_tag_name("cluster") {
+            continue;
+        }
+        let defaults = defaults.inherit(element)?;
+
+        for (name, offset) in instances(element)? {
+            let name = format!("{}{}", prefix, name);
+            let address = address.wrapping_add(offset);
+
+            if is_register {
+                // registers are read and written as whole bytes into a u64
+                if defaults.size == 0 || defaults.size > 64 || !defaults.size.is_multiple_of(8) {
+                    return Err(invalid(&name, "size must be 8 to 64 bits in whole bytes"));
+                }
+                registers.push(Register {
+                    description: text(element, "description").map(str::to_string),
+                    address,
+                    size: defaults.size,
+                    access: defaults.access.clone(),
+                    read_action: child(element, "readAction").is_some(),
+                    reset_value: defaults.reset_value,
+                    fields: fields(element, &name, defaults.size)?,
+                    name,
+                    word_safe: true,
+                });
+            } else {
+                collect(
+                    element,
+                    address,
+                    &format!("{}.", name),
+                    &defaults,
+                    registers,
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Work out which registers can be reached through the words around them.
+fn word_safety(registers: &mut [Register]) {
+    let plain = |register: &Register| {
+        matches!(register.access.as_deref(), None | Some("read-write")) && !register.read_action
+    };
+
+    for i in 0..registers.len() {
+        let address = registers[i].address as u64;
+        let len = registers[i].size as u64 / 8;
+        // the words holding the register
+        let (start, end) = (address & !3, (address + len + 3) & !3);
+        let whole = start == address && end == address + len;
+
+        let word_safe = whole
+            || registers.iter().enumerate().all(|(j, other)| {
+                let other_start = other.address as u64;
+                let other_end = other_start + other.size as u64 / 8;
+                // only registers with bytes in the same words matter
+                j == i || other_start >= end || other_end <= start || plain(other)
+            });
+        registers[i].word_safe = word_safe;
+    }
+}
+
+/// Names and offsets of a register or cluster, one per array element when it has a dim.
+fn instances(node: Node) -> Result<Vec<(String, u32)>, UtilError> {
+    let name = text(node, "name").ok_or_else(|| invalid(node.tag_name().name(), "missing name"))?;
+    let offset = number(node, "addressOffset")?
+        .ok_or_else(|| invalid(name, "missing addressOffset"))? as u32;
+
+    let dim = match number(node, "dim")? {
+        Some(dim) => dim as u32,
+        None => return Ok(vec![(name.to_string(), offset)]),
+    };
+    let increment = number(node, "dimIncrement")?
+        .ok_or_else(|| invalid(name, "dim without dimIncrement"))? as u32;
+
+    let indices: Vec<String> = match text(node, "dimIndex") {
+        Some(index) => match index.split_once('-') {
+            Some((first, last)) if index.bytes().all(|b| b.is_ascii_digit() || b == b'-') => {
+                let first: u32 = first
+                    .parse()
+                    .map_err(|_| invalid(name, "invalid dimIndex"))?;
+                let last: u32 = last
+                    .parse()
+                    .map_err(|_| invalid(name, "invalid dimIndex"))?;
+                (first..=last).map(|i| i.to_string()).collect()
+            }
+            _ => index.split(',').map(|i| i.trim().to_string()).collect(),
+        },
+        None => (0..dim).map(|i| i.to_string()).collect(),
+    };
+    if indices.len() != dim as usize {
+        return Err(invalid(name, "dimIndex doesn't match dim"));
+    }
+
+    Ok(indices
+        .iter()
+        .enumerate()
+        .map(|(i, index)| {
+            (
+                name.replace("%s", index),
+                offset.wrapping_add(i as u32 * increment),
+            )
+        })
+        .collect())
+}
+
+/// Fields of a register size bits wide.
+fn fields(register: Node, name: &str, size: u32) -> Result<Vec<Field>, UtilError> {
+    let nodes = match child(register, "fields") {
+        Some(fields) => elements(fields, "field"),
+        None => return Ok(vec![]),
+    };
+
+    let mut fields = vec![];
+    for node in nodes {
+        let field = text(node, "name").ok_or_else(|| invalid(name, "field missing name"))?;
+        let element = format!("{}.{}", name, field);
+
+        let (bit_offset, bit_width) = if let Some(range) = text(node, "bitRange") {
+            range
+                .strip_prefix('[')
+                .and_then(|range| range.strip_suffix(']'))
+                .and_then(|range| range.split_once(':'))
+                .and_then(|(msb, lsb)| Some((msb.parse::<u64>().ok()?, lsb.parse::<u64>().ok()?)))
+                .filter(|(msb, lsb)| msb >= lsb)
+                .map(|(msb, lsb)| (lsb, (msb - lsb).saturating_add(1)))
+                .ok_or_else(|| invalid(&element, "invalid bitRange"))?
+        } else if let (Some(lsb), Some(msb)) = (number(node, "lsb")?, number(node, "msb")?) {
+            if msb < lsb {
+                return Err(invalid(&element, "msb below lsb"));
+            }
+            (lsb, (msb - lsb).saturating_add(1))
+        } else {
+            let offset =
+                number(node, "bitOffset")?.ok_or_else(|| invalid(&element, "missing bit range"))?;
+            (offset, number(node, "bitWidth")?.unwrap_or(1))
+        };
+        if bit_width == 0
+            || bit_offset
+                .checked_add(bit_width)
+                .is_none_or(|end| end > size as u64)
+        {
+            return Err(invalid(&element, "bits outside the register"));
+        }
+        let (bit_offset, bit_width) = (bit_offset as u32, bit_width as u32);
+
+        let mut values = vec![];
+        for enumerated in node
+            .children()
+            .filter(|child| child.has_tag_name("enumeratedValues"))
+        {
+            for value in elements(enumerated, "enumeratedValue") {
+                // isDefault entries and don't care bits don't name a single value
+                let number = match text(value, "value").and_then(parse_number) {
+                    Some(number) => number,
+                    None => continue,
+                };
+                values.push(EnumeratedValue {
+                    name: text(value, "name").unwrap_or_default().to_string(),
+                    description: text(value, "description").map(str::to_string),
+                    value: number,
+                });
+            }
+        }
+
+        fields.push(Field {
+            name: field.to_string(),
+            description: text(node, "description").map(str::to_string),
+            bit_offset,
+            bit_width,
+            modified_write_values: text(node, "modifiedWriteValues")
+                .or_else(|| text(register, "modifiedWriteValues"))
+                .map(str::to_string),
+            values,
+        });
+    }
+    fields.sort_by_key(|field| field.bit_offset);
+    Ok(fields)
+}
+
+fn invalid(element: &str, reason: &'static str) -> UtilError {
+    UtilError::InvalidSvd {
+        element: element.to_string(),
+        reason,
+    }
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|child| child.has_tag_name(name))
+}
+
+fn elements<'a, 'input: 'a>(
+    node: Node<'a, 'input>,
+    name: &'a str,
+) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    node.children()
+        .filter(move |child| child.has_tag_name(name))
+}
+
+/// Trimmed text of a child element, descriptions may span lines.
+fn text<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    child(node, name)
+        .and_then(|child| child.text())
+        .map(str::trim)
+}
+
+fn number(node: Node, name: &str) -> Result<Option<u64>, UtilError> {
+    match text(node, name) {
+        Some(value) => parse_number(value)
+            .map(Some)
+            .ok_or_else(|| invalid(name, "invalid number")),
+        None => Ok(None),
+    }
+}
+
+/// SVD scaled non negative integers, decimal, 0x hex or #binary.
+fn parse_number(value: &str) -> Option<u64> {
+    if let Some(hex) = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        u64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = value.strip_prefix('#') {
+        u64::from_str_radix(binary, 2).ok()
+    } else {
+        value.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SVD: &str = include_str!("testdata/port.svd");
+
+    #[test]
+    fn clusters_arrays_and_derived() {
+        let device = parse(SVD).unwrap();
+        assert_eq!(device.name, "TEST");
+
+        let port = device.peripheral("port").unwrap();
+        assert_eq!(port.base_address, 0x4100_8000);
+        let names: Vec<_> = port
+            .registers
+            .iter()
+            .map(|register| (register.name.as_str(), register.address, register.size))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("GROUP[0].DIR", 0x4100_8000, 32),
+                ("GROUP[0].OUTSET", 0x4100_8018, 32),
+                ("GROUP[0].PINCFG0", 0x4100_8040, 8),
+                ("GROUP[0].PINCFG1", 0x4100_8041, 8),
+                ("GROUP[1].DIR", 0x4100_8080, 32),
+                ("GROUP[1].OUTSET", 0x4100_8098, 32),
+                ("GROUP[1].PINCFG0", 0x4100_80C0, 8),
+                ("GROUP[1].PINCFG1", 0x4100_80C1, 8),
+                ("EVCTRL", 0x4100_8100, 32),
+                ("INTFLAG", 0x4100_8104, 32),
+            ]
+        );
+
+        let outset = port.register("group[1].outset").unwrap();
+        assert!(!outset.readable());
+        assert!(outset.writable());
+
+        let pincfg = port.register("GROUP[0].PINCFG1").unwrap();
+        assert_eq!(pincfg.reset_value, Some(0));
+        assert!(pincfg.word_safe);
+        let drvstr = pincfg.field("DRVSTR").unwrap();
+        assert_eq!((drvstr.bit_offset, drvstr.bit_width), (6, 1));
+        let pull = pincfg.field("PMUXEN").unwrap();
+        assert_eq!(pull.value_of(1).unwrap().name, "ENABLED");
+
+        let evctrl = port.register("EVCTRL").unwrap();
+        let evact = evctrl.field("EVACT0").unwrap();
+        assert_eq!((evact.bit_offset, evact.bit_width), (5, 2));
+        assert_eq!(evact.parse_value("toggle").unwrap(), 3);
+        assert_eq!(evact.extract(0x60), 3);
+        assert_eq!(evact.insert(0xFF, 1).unwrap(), 0xBF);
+        assert!(matches!(
+            evact.insert(0, 4),
+            Err(UtilError::InvalidFieldValue { .. })
+        ));
+
+        // same registers, rebased
+        let port_b = device.peripheral("PORT_B").unwrap();
+        assert_eq!(port_b.register("EVCTRL").unwrap().address, 0x4100_9100);
+        assert_eq!(port_b.description, port.description);
+    }
+
+    #[test]
+    fn bad_svd() {
+        assert!(matches!(parse("<device"), Err(UtilError::Xml(_))));
+        assert!(matches!(
+            parse("<device><peripherals><peripheral><name>A</name></peripheral></peripherals></device>"),
+            Err(UtilError::InvalidSvd { .. })
+        ));
+
+        // wider than a u64, or not whole bytes
+        let register = |size| {
+            format!(
+                "<device><peripherals><peripheral><name>A</name><baseAddress>0</baseAddress><registers>\
+                 <register><name>R</name><addressOffset>0</addressOffset><size>{}</size></register>\
+                 </registers></peripheral></peripherals></device>",
+                size
+            )
+        };
+        assert!(parse(&register("16")).is_ok());
+        for size in ["128", "12", "0"] {
+            assert!(matches!(
+                parse(&register(size)),
+                Err(UtilError::InvalidSvd { element, .. }) if element == "R"
+            ));
+        }
+
+        // fields of no width or past the end of the register
+        let field = |bits| {
+            format!(
+                "<device><peripherals><peripheral><name>A</name><baseAddress>0</baseAddress><registers>\
+                 <register><name>R</name><addressOffset>0</addressOffset><size>16</size>\
+                 <fields><field><name>F</name>{}</field></fields></register>\
+                 </registers></peripheral></peripherals></device>",
+                bits
+            )
+        };
+        assert!(parse(&field("<bitOffset>8</bitOffset><bitWidth>8</bitWidth>")).is_ok());
+        for bits in [
+            "<bitOffset>8</bitOffset><bitWidth>0</bitWidth>",
+            "<bitOffset>64</bitOffset>",
+            "<bitOffset>12</bitOffset><bitWidth>5</bitWidth>",
+            "<bitRange>[16:0]</bitRange>",
+            "<lsb>3</lsb><msb>2</msb>",
+            "<lsb>0</lsb><msb>0xFFFFFFFFFFFFFFFF</msb>",
+        ] {
+            assert!(matches!(
+                parse(&field(bits)),
+                Err(UtilError::InvalidSvd { element, .. }) if element == "R.F"
+            ));
+        }
+    }
+
+    #[test]
+    fn narrow_registers_next_to_side_effects() {
+        let device = parse(
+            "<device><peripherals><peripheral><name>A</name><baseAddress>0</baseAddress><registers>\
+             <register><name>CTRL</name><addressOffset>0</addressOffset><size>8</size></register>\
+             <register><name>DATA</name><addressOffset>1</addressOffset><size>8</size><readAction>clear</readAction></register>\
+             <register><name>STATUS</name><addressOffset>4</addressOffset><size>16</size></register>\
+             <register><name>MODE</name><addressOffset>6</addressOffset><size>8</size></register>\
+             <register><name>WORD</name><addressOffset>8</addressOffset></register>\
+             </registers></peripheral></peripherals></device>",
+        )
+        .unwrap();
+        let safe: Vec<_> = device.peripherals[0]
+            .registers
+            .iter()
+            .map(|register| (register.name.as_str(), register.word_safe))
+            .collect();
+        assert_eq!(
+            safe,
+            vec![
+                ("CTRL", false),
+                ("DATA", true),
+                ("STATUS", true),
+                ("MODE", true),
+                ("WORD", true)
+            ]
+        );
+
+        #[cfg(feature = "emulator")]
+        {
+            let emulator = crate::emulator::Emulator::default();
+            let ctrl = device.peripherals[0].register("CTRL").unwrap();
+            assert!(matches!(
+                read_register(ctrl, &emulator.bininfo(), &emulator),
+                Err(UtilError::InvalidSvd { .. })
+            ));
+        }
+    }
+
+    #[cfg(feature = "emulator")]
+    #[test]
+    fn write_fields_keeps_the_rest() {
+        let device = parse(SVD).unwrap();
+        let port = device.peripheral("PORT").unwrap();
+        let emulator = crate::emulator::Emulator::default();
+        let bininfo = emulator.bininfo();
+        emulator.set_word(0x4100_8040, 0x0403_0201);
+
+        // an 8 bit register in the middle of a word
+        let pincfg = port.register("GROUP[0].PINCFG1").unwrap();
+        assert_eq!(read_register(pincfg, &bininfo, &emulator).unwrap(), 0x02);
+        let value = write_fields(
+            pincfg,
+            &[("PMUXEN", "enabled"), ("DRVSTR", "1")],
+            &bininfo,
+            &emulator,
+        )
+        .unwrap();
+        assert_eq!(value, 0x43);
+        assert_eq!(emulator.word(0x4100_8040), 0x0403_4301);
+
+        // write only, starts from zero
+        emulator.set_word(0x4100_8018, 0xFFFF_FFFF);
+        let outset = port.register("GROUP[0].OUTSET").unwrap();
+        assert_eq!(
+            write_fields(outset, &[("OUTSET", "0x10")], &bininfo, &emulator).unwrap(),
+            0x10
+        );
+        assert_eq!(emulator.word(0x4100_8018), 0x10);
+
+        // write 1 to clear flags that aren't being changed are written as 0, so they stay set
+        emulator.set_word(0x4100_8104, 0x103);
+        let intflag = port.register("INTFLAG").unwrap();
+        assert_eq!(intflag.field("PIN0").unwrap().neutral(), Some(0));
+        assert_eq!(intflag.field("ENABLE").unwrap().neutral(), None);
+        assert_eq!(
+            write_fields(intflag, &[("PIN1", "1")], &bininfo, &emulator).unwrap(),
+            0x102
+        );
+        assert_eq!(emulator.word(0x4100_8104), 0x102);
+    }
+}
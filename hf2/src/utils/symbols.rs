@@ -0,0 +1,548 @@
+use super::UtilError;
+use crate::{write_memory, BinInfoResponse, ReadWrite};
+use gimli::{constants::*, AttributeValue, EndianSlice, Operation, RunTimeEndian, UnitOffset};
+use goblin::elf::{sym::STT_OBJECT, Elf};
+use std::path::PathBuf;
+use std::{fs::File, io::Read};
+
+type Reader<'a> = EndianSlice<'a, RunTimeEndian>;
+
+/// A variable from the elf symbol table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    /// Demangled without the hash, like blinky::STATE.
+    pub name: String,
+    pub address: u32,
+    pub size: u32,
+}
+
+/// Where a variable, or a field inside one, lives in memory.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Variable {
+    pub address: u32,
+    pub size: u32,
+    /// Whether the debug info says it's a signed integer, false when there's no debug info.
+    pub signed: bool,
+}
+
+impl Variable {
+    /// Little endian bytes of value, which must fit the variable's size. Negative values are two's complement.
+    pub fn encode(&self, value: i64) -> Result<Vec<u8>, UtilError> {
+        let error = UtilError::ValueTooLarge {
+            value,
+            size: self.size,
+        };
+        if self.size == 0 || self.size > 8 {
+            return Err(error);
+        }
+        if self.size < 8 {
+            let bits = self.size * 8;
+            if value < -(1 << (bits - 1)) || value >= 1 << bits {
+                return Err(error);
+            }
+        }
+        Ok(value.to_le_bytes()[..self.size as usize].to_vec())
+    }
+
+    /// Integer held in little endian bytes read from the variable, sign extended if it's signed. None for more than 8 bytes.
+    pub fn decode(&self, bytes: &[u8]) -> Option<i128> {
+        if bytes.is_empty() || bytes.len() > 8 {
+            return None;
+        }
+        let negative = self.signed && bytes[bytes.len() - 1] & 0x80 != 0;
+        let mut value = [if negative { 0xFF } else { 0 }; 8];
+        value[..bytes.len()].copy_from_slice(bytes);
+        Some(if self.signed {
+            i64::from_le_bytes(value) as i128
+        } else {
+            u64::from_le_bytes(value) as i128
+        })
+    }
+}
+
+/// Set a variable to value, reading and writing back the words around it so neighbouring variables are kept.
+pub fn write_variable(
+    variable: &Variable,
+    value: i64,
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+) -> Result<(), UtilError> {
+    let data = variable.encode(value)?;
+    write_memory(d, bininfo, variable.address, &data)?;
+    Ok(())
+}
+
+/// Why a field couldn't be found in the debug info.
+enum FieldError {
+    Dwarf(gimli::Error),
+    Unsupported(&'static str),
+}
+
+impl From<gimli::Error> for FieldError {
+    fn from(err: gimli::Error) -> Self {
+        FieldError::Dwarf(err)
+    }
+}
+
+/// Variables of an elf, found by name.
+pub struct Symbols {
+    data: Vec<u8>,
+    symbols: Vec<Symbol>,
+}
+
+impl Symbols {
+    /// Read the symbol table of an elf file.
+    pub fn read(path: PathBuf) -> Result<Self, UtilError> {
+        let mut file = File::open(path).map_err(UtilError::File)?;
+        let mut data = vec![];
+        file.read_to_end(&mut data).map_err(UtilError::File)?;
+        Self::parse(data)
+    }
+
+    pub fn parse(data: Vec<u8>) -> Result<Self, UtilError> {
+        let elf = Elf::parse(&data).map_err(UtilError::Elf)?;
+
+        let symbols = elf
+            .syms
+            .iter()
+            .filter(|sym| sym.st_type() == STT_OBJECT)
+            .filter_map(|sym| {
+                let name = elf.strtab.get(sym.st_name)?.ok()?;
+                Some(Symbol {
+                    name: demangle(name),
+                    address: sym.st_value as u32,
+                    size: sym.st_size as u32,
+                })
+            })
+            .collect();
+
+        Ok(Self { data, symbols })
+    }
+
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    /// Find a variable by its full or last path component, like blinky::STATE or STATE. Fields follow after dots, like STATE.config.threshold, and are looked up in the debug info.
+    pub fn resolve(&self, path: &str) -> Result<Variable, UtilError> {
+        let error = |reason| UtilError::Symbol {
+            name: path.to_string(),
+            reason,
+        };
+
+        // C statics get names like counter.1234, so try the whole thing first
+        let (symbol, fields) = match self.find(path) {
+            Ok(symbol) => (symbol, vec![]),
+            Err(_) => {
+                let mut parts = path.split('.');
+                let root = parts.next().unwrap_or_default();
+                (self.find(root)?, parts.collect())
+            }
+        };
+
+        if fields.is_empty() {
+            if symbol.size == 0 {
+                return Err(error("symbol has no size"));
+            }
+            // debug info, if there is any, says whether it's signed
+            let signed = matches!(
+                self.field(symbol, &[]),
+                Ok(Some(Variable { signed: true, .. }))
+            );
+            return Ok(Variable {
+                address: symbol.address,
+                size: symbol.size,
+                signed,
+            });
+        }
+
+        match self.field(symbol, &fields) {
+            Ok(Some(variable)) => Ok(variable),
+            Ok(None) => Err(error("no such field in the debug info")),
+            Err(FieldError::Dwarf(err)) => {
+                log::debug!("debug info for {}: {}", path, err);
+                Err(error("couldn't read debug info"))
+            }
+            Err(FieldError::Unsupported(reason)) => Err(error(reason)),
+        }
+    }
+
+    fn find(&self, name: &str) -> Result<&Symbol, UtilError> {
+        let error = |reason| UtilError::Symbol {
+            name: name.to_string(),
+            reason,
+        };
+
+        if let Some(symbol) = self.symbols.iter().find(|symbol| symbol.name == name) {
+            return Ok(symbol);
+        }
+        let mut matches = self
+            .symbols
+            .iter()
+            .filter(|symbol| symbol.name.rsplit("::").next() == Some(name));
+        match (matches.next(), matches.next()) {
+            (Some(symbol), None) => Ok(symbol),
+            (Some(_), Some(_)) => Err(error("ambiguous, give its full path")),
+            _ => Err(error("not found")),
+        }
+    }
+
+    /// Walk fields from the variable's type in the debug info, None if the variable or a field isn't there.
+    fn field(&self, symbol: &Symbol, fields: &[&str]) -> Result<Option<Variable>, FieldError> {
+        let elf = match Elf::parse(&self.data) {
+            Ok(elf) => elf,
+            Err(_) => return Ok(None),
+        };
+        let endian = if elf.little_endian {
+            RunTimeEndian::Little
+        } else {
+            RunTimeEndian::Big
+        };
+        let dwarf = gimli::Dwarf::load(|id| -> Result<Reader, gimli::Error> {
+            let data = elf
+                .section_headers
+                .iter()
+                .find(|sh| {
+                    elf.shdr_strtab.get(sh.sh_name).and_then(|name| name.ok()) == Some(id.name())
+                })
+                .and_then(|sh| {
+                    self.data
+                        .get(sh.sh_offset as usize..)?
+                        .get(..sh.sh_size as usize)
+                })
+                .unwrap_or(&[]);
+            Ok(EndianSlice::new(data, endian))
+        })?;
+
+        let name = symbol.name.rsplit("::").next().unwrap_or_default();
+
+        let mut units = dwarf.units();
+        while let Some(header) = units.next()? {
+            let unit = dwarf.unit(header)?;
+            let mut entries = unit.entries();
+            while let Some((_, entry)) = entries.next_dfs()? {
+                if entry.tag() != DW_TAG_variable {
+                    continue;
+                }
+                let matches_name = match entry.attr_value(DW_AT_name)? {
+                    Some(value) => dwarf.attr_string(&unit, value)?.slice() == name.as_bytes(),
+                    None => false,
+                };
+                // the same name may be declared in many units, only one has the address
+                let address = match entry.attr_value(DW_AT_location)? {
+                    Some(AttributeValue::Exprloc(mut expression)) => {
+                        match Operation::parse(&mut expression.0, unit.encoding())? {
+                            Operation::Address { address } => Some(address),
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                };
+                if !matches_name || address != Some(symbol.address as u64) {
+                    continue;
+                }
+
+                let mut offset = 0;
+                let mut ty = entry.attr_value(DW_AT_type)?;
+                for field in fields {
+                    let parent = match strip(&unit, ty)? {
+                        Some(parent) => parent,
+                        None => return Ok(None),
+                    };
+                    let mut tree = unit.entries_tree(Some(parent))?;
+                    let mut children = tree.root()?.children();
+
+                    ty = None;
+                    while let Some(child) = children.next()? {
+                        let member = child.entry();
+                        if member.tag() != DW_TAG_member {
+                            continue;
+                        }
+                        if let Some(value) = member.attr_value(DW_AT_name)? {
+                            if dwarf.attr_string(&unit, value)?.slice() == field.as_bytes() {
+                                // a bitfield shares its bytes with its neighbours
+                                if member.attr_value(DW_AT_bit_size)?.is_some()
+                                    || member.attr_value(DW_AT_data_bit_offset)?.is_some()
+                                {
+                                    return Err(FieldError::Unsupported("bitfields not supported"));
+                                }
+                                offset += member_offset(&unit, member)?;
+                                ty = member.attr_value(DW_AT_type)?;
+                                break;
+                            }
+                        }
+                    }
+                    if ty.is_none() {
+                        return Ok(None);
+                    }
+                }
+
+                let (size, signed) = match strip(&unit, ty)? {
+                    Some(offset) => {
+                        let entry = unit.entry(offset)?;
+                        let size = entry
+                            .attr_value(DW_AT_byte_size)?
+                            .and_then(|value| value.udata_value());
+                        let signed = entry.tag() == gimli::DW_TAG_base_type
+                            && matches!(
+                                entry.attr_value(DW_AT_encoding)?,
+                                Some(AttributeValue::Encoding(
+                                    gimli::DW_ATE_signed | gimli::DW_ATE_signed_char
+                                ))
+                            );
+                        (size, signed)
+                    }
+                    None => (None, false),
+                };
+                return Ok(size.map(|size| Variable {
+                    address: symbol.address + offset as u32,
+                    size: size as u32,
+                    signed,
+                }));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Rust paths without the hash or the .0 suffixes llvm adds, other names as they are.
+fn demangle(name: &str) -> String {
+    match rustc_demangle::try_demangle(name) {
+        Ok(demangled) => {
+            let mut name = format!("{:#}", demangled);
+            while let Some((path, suffix)) = name.rsplit_once('.') {
+                if suffix.is_empty() || !suffix.bytes().all(|b| b.is_ascii_digit()) {
+                    break;
+                }
+                name.truncate(path.len());
+            }
+            name
+        }
+        Err(_) => name.to_string(),
+    }
+}
+
+/// Offset of a member into its struct, a constant or an expression adding one, like older DWARF versions use.
+fn member_offset(
+    unit: &gimli::Unit<Reader>,
+    member: &gimli::DebuggingInformationEntry<Reader>,
+) -> Result<u64, FieldError> {
+    match member.attr_value(DW_AT_data_member_location)? {
+        // union members and first fields may leave it out
+        None => Ok(0),
+        Some(AttributeValue::Exprloc(mut expression)) => {
+            match Operation::parse(&mut expression.0, unit.encoding())? {
+                Operation::PlusConstant { value } if expression.0.is_empty() => Ok(value),
+                _ => Err(FieldError::Unsupported("field location isn't a constant")),
+            }
+        }
+        Some(value) => value
+            .udata_value()
+            .ok_or(FieldError::Unsupported("field location isn't a constant")),
+    }
+}
+
+/// Follow a type reference through typedefs, const and volatile to the type that has a size or members.
+fn strip<'a>(
+    unit: &gimli::Unit<Reader<'a>>,
+    mut ty: Option<AttributeValue<Reader<'a>>>,
+) -> Result<Option<UnitOffset>, gimli::Error> {
+    loop {
+        let offset = match ty {
+            Some(AttributeValue::UnitRef(offset)) => offset,
+            _ => return Ok(None),
+        };
+        let entry = unit.entry(offset)?;
+        match entry.tag() {
+            gimli::DW_TAG_typedef | gimli::DW_TAG_const_type | gimli::DW_TAG_volatile_type => {
+                ty = entry.attr_value(DW_AT_type)?
+            }
+            _ => return Ok(Some(offset)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn c_variables_and_fields() {
+        // testdata/vars.c built with gcc -m32 -gdwarf-4 -nostdlib -static
+        let symbols = Symbols::read(
+            [env!("CARGO_MANIFEST_DIR"), "src/utils/testdata/vars"]
+                .iter()
+                .collect(),
+        )
+        .unwrap();
+
+        let resolve = |path| symbols.resolve(path).unwrap();
+        assert_eq!(
+            resolve("COUNTER"),
+            Variable {
+                address: 0x0804_b000,
+                size: 4,
+                signed: false
+            }
+        );
+        assert_eq!(resolve("STATE").size, 16);
+        assert_eq!(
+            resolve("STATE.mode"),
+            Variable {
+                address: 0x0804_b004,
+                size: 4,
+                signed: false
+            }
+        );
+        assert_eq!(
+            resolve("STATE.config.offset"),
+            Variable {
+                address: 0x0804_b004 + 8 + 6,
+                size: 2,
+                signed: true
+            }
+        );
+        // through a typedef and const
+        assert_eq!(
+            resolve("BACKUP.gain"),
+            Variable {
+                address: 0x0804_b014 + 4,
+                size: 1,
+                signed: false
+            }
+        );
+        assert_eq!(
+            resolve("CONFIG.threshold"),
+            Variable {
+                address: 0x0804_a000,
+                size: 4,
+                signed: false
+            }
+        );
+
+        assert!(matches!(
+            symbols.resolve("STATE.nope"),
+            Err(UtilError::Symbol { .. })
+        ));
+        assert!(matches!(
+            symbols.resolve("NOPE"),
+            Err(UtilError::Symbol { .. })
+        ));
+
+        // a bitfield's bytes hold its neighbours too, the plain field next to them is fine
+        assert!(matches!(
+            symbols.resolve("FLAGS.level"),
+            Err(UtilError::Symbol {
+                reason: "bitfields not supported",
+                ..
+            })
+        ));
+        assert_eq!(
+            resolve("FLAGS.count"),
+            Variable {
+                address: 0x0804_b01c + 4,
+                size: 4,
+                signed: true
+            }
+        );
+    }
+
+    #[test]
+    fn decode_sign_extends() {
+        let mut short = Variable {
+            address: 0,
+            size: 2,
+            signed: true,
+        };
+        assert_eq!(short.decode(&[0xFB, 0xFF]), Some(-5));
+        assert_eq!(short.decode(&[0x05, 0x00]), Some(5));
+        short.signed = false;
+        assert_eq!(short.decode(&[0xFB, 0xFF]), Some(65531));
+
+        let word = Variable {
+            address: 0,
+            size: 8,
+            signed: false,
+        };
+        assert_eq!(word.decode(&[0xFF; 8]), Some(u64::MAX as i128));
+        assert_eq!(word.decode(&[0; 9]), None);
+    }
+
+    #[test]
+    fn encode_checks_size() {
+        let byte = Variable {
+            address: 0,
+            size: 1,
+            signed: false,
+        };
+        assert_eq!(byte.encode(255).unwrap(), vec![0xFF]);
+        assert_eq!(byte.encode(-128).unwrap(), vec![0x80]);
+        assert!(matches!(
+            byte.encode(256),
+            Err(UtilError::ValueTooLarge {
+                value: 256,
+                size: 1
+            })
+        ));
+        assert!(byte.encode(-129).is_err());
+
+        let word = Variable {
+            address: 0,
+            size: 8,
+            signed: false,
+        };
+        assert_eq!(word.encode(-1).unwrap(), vec![0xFF; 8]);
+    }
+
+    #[cfg(feature = "emulator")]
+    #[test]
+    fn write_field() {
+        let symbols = Symbols::read(
+            [env!("CARGO_MANIFEST_DIR"), "src/utils/testdata/vars"]
+                .iter()
+                .collect(),
+        )
+        .unwrap();
+        let emulator = crate::emulator::Emulator::default();
+        let bininfo = emulator.bininfo();
+        emulator.set_word(0x0804_b010, 0x1122_3344);
+
+        // the upper half of the word holding gain and offset
+        let offset = symbols.resolve("STATE.config.offset").unwrap();
+        write_variable(&offset, -5, &bininfo, &emulator).unwrap();
+        assert_eq!(emulator.word(0x0804_b010), 0xFFFB_3344);
+
+        assert!(matches!(
+            write_variable(&offset, 0x1_0000, &bininfo, &emulator),
+            Err(UtilError::ValueTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn rust_paths() {
+        let symbols = Symbols::read(
+            [
+                env!("CARGO_MANIFEST_DIR"),
+                "src/utils/testdata/blinky_1.44.0",
+            ]
+            .iter()
+            .collect(),
+        )
+        .unwrap();
+        assert!(symbols
+            .symbols()
+            .iter()
+            .any(|symbol| symbol.name == "cortex_m::peripheral::TAKEN"));
+
+        let taken = Variable {
+            address: 0x2000_0001,
+            size: 1,
+            signed: false,
+        };
+        assert_eq!(symbols.resolve("TAKEN").unwrap(), taken);
+        assert_eq!(
+            symbols.resolve("cortex_m::peripheral::TAKEN").unwrap(),
+            taken
+        );
+    }
+}
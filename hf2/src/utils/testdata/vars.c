@@ -0,0 +1,29 @@
+enum mode { IDLE, RUNNING, FAULT };
+
+struct config {
+    unsigned int threshold;
+    unsigned char gain;
+    short offset;
+};
+
+struct state {
+    enum mode mode;
+    unsigned short ticks;
+    struct config config;
+};
+
+unsigned int COUNTER = 7;
+struct state STATE = { RUNNING, 3, { 100, 2, -5 } };
+const struct config CONFIG = { 42, 1, 0 };
+typedef struct config config_t;
+config_t BACKUP;
+
+struct flags {
+    unsigned int enable : 1;
+    unsigned int level : 3;
+    int count;
+};
+
+struct flags FLAGS;
+
+void _start(void) {}
@@ -0,0 +1,430 @@
+use super::{load, Image, UtilError};
+use crate::FamilyId;
+use scroll::{Pwrite, LE};
+use std::path::PathBuf;
+use std::{
+    fs::File,
+    io::{Read, Write},
+};
+
+pub const BLOCK_SIZE: usize = 512;
+/// Most payload a block can carry, though 256 is what most tools use.
+pub const MAX_PAYLOAD_SIZE: usize = 476;
+/// Payload size written by uf2conv.py, which some bootloaders expect.
+pub const PAYLOAD_SIZE: usize = 256;
+
+const MAGIC_START0: u32 = 0x0A32_4655;
+const MAGIC_START1: u32 = 0x9E5D_5157;
+const MAGIC_END: u32 = 0x0AB1_6F30;
+
+/// Block is not to be written to main flash, for example comments or debug info.
+pub const FLAG_NOT_MAIN_FLASH: u32 = 0x0000_0001;
+pub const FLAG_FILE_CONTAINER: u32 = 0x0000_1000;
+/// The file size field holds a family id instead.
+pub const FLAG_FAMILY_ID_PRESENT: u32 = 0x0000_2000;
+pub const FLAG_MD5_PRESENT: u32 = 0x0000_4000;
+pub const FLAG_EXTENSION_TAGS_PRESENT: u32 = 0x0000_8000;
+
+/// A single 512 byte UF2 block.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub flags: u32,
+    pub target_address: u32,
+    /// Sequential block number, starting at 0.
+    pub block_no: u32,
+    /// Total number of blocks in the file.
+    pub num_blocks: u32,
+    /// Family id if FLAG_FAMILY_ID_PRESENT is set, otherwise the file size field.
+    pub file_size_or_family_id: u32,
+    pub data: Vec<u8>,
+}
+
+impl Block {
+    pub fn family_id(&self) -> Option<FamilyId> {
+        if self.flags & FLAG_FAMILY_ID_PRESENT != 0 {
+            Some(self.file_size_or_family_id.into())
+        } else {
+            None
+        }
+    }
+
+    /// Whether the payload is meant for main flash.
+    pub fn is_main_flash(&self) -> bool {
+        self.flags & FLAG_NOT_MAIN_FLASH == 0
+    }
+
+    /// Serialize into a 512 byte block.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, UtilError> {
+        let invalid = |reason| UtilError::InvalidUf2 {
+            block: self.block_no as usize,
+            reason,
+        };
+        if self.data.len() > MAX_PAYLOAD_SIZE {
+            return Err(invalid("payload too large"));
+        }
+
+        let mut buffer = vec![0_u8; BLOCK_SIZE];
+        let mut offset = 0;
+        let overflow = |_: scroll::Error| invalid("block overflowed 512 bytes");
+
+        buffer
+            .gwrite_with(MAGIC_START0, &mut offset, LE)
+            .map_err(overflow)?;
+        buffer
+            .gwrite_with(MAGIC_START1, &mut offset, LE)
+            .map_err(overflow)?;
+        buffer
+            .gwrite_with(self.flags, &mut offset, LE)
+            .map_err(overflow)?;
+        buffer
+            .gwrite_with(self.target_address, &mut offset, LE)
+            .map_err(overflow)?;
+        buffer
+            .gwrite_with(self.data.len() as u32, &mut offset, LE)
+            .map_err(overflow)?;
+        buffer
+            .gwrite_with(self.block_no, &mut offset, LE)
+            .map_err(overflow)?;
+        buffer
+            .gwrite_with(self.num_blocks, &mut offset, LE)
+            .map_err(overflow)?;
+        buffer
+            .gwrite_with(self.file_size_or_family_id, &mut offset, LE)
+            .map_err(overflow)?;
+        buffer
+            .gwrite(self.data.as_slice(), &mut offset)
+            .map_err(overflow)?;
+        buffer
+            .pwrite_with(MAGIC_END, BLOCK_SIZE - 4, LE)
+            .map_err(overflow)?;
+
+        Ok(buffer)
+    }
+
+    /// Parse a 512 byte block, index is its position in the file and is what errors about a truncated block report.
+    pub fn from_bytes(bytes: &[u8], index: usize) -> Result<Self, UtilError> {
+        if bytes.len() < BLOCK_SIZE {
+            return Err(UtilError::InvalidUf2 {
+                block: index,
+                reason: "block shorter than 512 bytes",
+            });
+        }
+        let word = |offset: usize| {
+            u32::from_le_bytes([
+                bytes[offset],
+                bytes[offset + 1],
+                bytes[offset + 2],
+                bytes[offset + 3],
+            ])
+        };
+
+        let magic_start0 = word(0);
+        let magic_start1 = word(4);
+        let flags = word(8);
+        let target_address = word(12);
+        let payload_size = word(16) as usize;
+        let block_no = word(20);
+        let num_blocks = word(24);
+        let file_size_or_family_id = word(28);
+        let magic_end = word(BLOCK_SIZE - 4);
+
+        let invalid = |reason| UtilError::InvalidUf2 {
+            block: block_no as usize,
+            reason,
+        };
+
+        if magic_start0 != MAGIC_START0 || magic_start1 != MAGIC_START1 {
+            return Err(invalid("bad start magic"));
+        }
+        if magic_end != MAGIC_END {
+            return Err(invalid("bad end magic"));
+        }
+        if payload_size > MAX_PAYLOAD_SIZE {
+            return Err(invalid("payload too large"));
+        }
+
+        Ok(Block {
+            flags,
+            target_address,
+            block_no,
+            num_blocks,
+            file_size_or_family_id,
+            data: bytes[32..][..payload_size].to_vec(),
+        })
+    }
+}
+
+/// Parse every block of a UF2 file.
+pub fn parse(bytes: &[u8]) -> Result<Vec<Block>, UtilError> {
+    if !bytes.len().is_multiple_of(BLOCK_SIZE) {
+        return Err(UtilError::InvalidUf2 {
+            block: bytes.len() / BLOCK_SIZE,
+            reason: "file isn't a whole number of 512 byte blocks",
+        });
+    }
+
+    bytes
+        .chunks(BLOCK_SIZE)
+        .enumerate()
+        .map(|(index, block)| Block::from_bytes(block, index))
+        .collect()
+}
+
+/// Read and parse a UF2 file.
+pub fn read(path: PathBuf) -> Result<Vec<Block>, UtilError> {
+    let mut file = File::open(path).map_err(UtilError::File)?;
+    let mut buffer = vec![];
+    file.read_to_end(&mut buffer).map_err(UtilError::File)?;
+
+    parse(&buffer)
+}
+
+/// Convert an ELF, Intel HEX, S-record, or a raw binary placed at address, into blocks tagged with family_id if given.
+pub fn from_file(
+    path: PathBuf,
+    address: Option<u32>,
+    family_id: Option<FamilyId>,
+) -> Result<Vec<Block>, UtilError> {
+    let image = load(path, address)?;
+
+    if image.is_empty() {
+        return Err(UtilError::InvalidBinary);
+    }
+
+    from_image(&image, PAYLOAD_SIZE, family_id)
+}
+
+/// Serialize blocks and write them to a UF2 file.
+pub fn write(path: PathBuf, blocks: &[Block]) -> Result<(), UtilError> {
+    let bytes = to_bytes(blocks)?;
+    File::create(path)
+        .and_then(|mut file| file.write_all(&bytes))
+        .map_err(UtilError::WriteFile)
+}
+
+/// Split a contiguous binary into blocks carrying payload_size bytes each, tagged with family_id if given.
+pub fn from_bin(
+    binary: &[u8],
+    address: u32,
+    payload_size: usize,
+    family_id: Option<FamilyId>,
+) -> Result<Vec<Block>, UtilError> {
+    from_image(&Image::from_bin(binary, address)?, payload_size, family_id)
+}
+
+/// Split every segment of an image into blocks carrying up to payload_size bytes each, tagged with family_id if given. Gaps between segments get no blocks.
+pub fn from_image(
+    image: &Image,
+    payload_size: usize,
+    family_id: Option<FamilyId>,
+) -> Result<Vec<Block>, UtilError> {
+    if payload_size == 0 || payload_size > MAX_PAYLOAD_SIZE {
+        return Err(UtilError::InvalidUf2 {
+            block: 0,
+            reason: "payload size must be between 1 and 476",
+        });
+    }
+
+    let (flags, file_size_or_family_id) = match family_id {
+        Some(family_id) => (FLAG_FAMILY_ID_PRESENT, family_id.into()),
+        None => (0, 0),
+    };
+
+    let chunks: Vec<(u32, &[u8])> = image
+        .segments()
+        .iter()
+        .flat_map(|segment| {
+            segment
+                .data
+                .chunks(payload_size)
+                .enumerate()
+                .map(move |(i, data)| (segment.address + (i * payload_size) as u32, data))
+        })
+        .collect();
+    let num_blocks = chunks.len() as u32;
+
+    Ok(chunks
+        .into_iter()
+        .enumerate()
+        .map(|(i, (target_address, data))| Block {
+            flags,
+            target_address,
+            block_no: i as u32,
+            num_blocks,
+            file_size_or_family_id,
+            data: data.to_vec(),
+        })
+        .collect())
+}
+
+/// Sparse image of the main flash blocks, which may come in any order but must agree where they overlap.
+pub fn to_image(blocks: &[Block]) -> Result<Image, UtilError> {
+    let mut image = Image::new();
+    for block in blocks.iter().filter(|block| block.is_main_flash()) {
+        image.add(block.target_address, &block.data)?;
+    }
+    Ok(image)
+}
+
+/// Serialize blocks into a UF2 file.
+pub fn to_bytes(blocks: &[Block]) -> Result<Vec<u8>, UtilError> {
+    let mut buffer = Vec::with_capacity(blocks.len() * BLOCK_SIZE);
+    for block in blocks {
+        buffer.extend_from_slice(&block.to_bytes()?);
+    }
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A block built by hand the way the uf2conv.py reference tool lays it out.
+    fn fixture(flags: u32, family: u32) -> Vec<u8> {
+        let mut block = vec![];
+        block.extend_from_slice(&[0x55, 0x46, 0x32, 0x0A]); // "UF2\n"
+        block.extend_from_slice(&[0x57, 0x51, 0x5D, 0x9E]);
+        block.extend_from_slice(&flags.to_le_bytes());
+        block.extend_from_slice(&[0x00, 0x40, 0x00, 0x00]); // 0x4000
+        block.extend_from_slice(&[0x00, 0x01, 0x00, 0x00]); // 256 bytes
+        block.extend_from_slice(&[0x01, 0x00, 0x00, 0x00]); // block 1
+        block.extend_from_slice(&[0x02, 0x00, 0x00, 0x00]); // of 2
+        block.extend_from_slice(&family.to_le_bytes());
+        block.extend((0..476).map(|i| if i < 256 { i as u8 } else { 0 }));
+        block.extend_from_slice(&[0x30, 0x6F, 0xB1, 0x0A]);
+        block
+    }
+
+    #[test]
+    fn parse_block() {
+        let blocks = parse(&fixture(FLAG_FAMILY_ID_PRESENT, 0x5511_4460)).unwrap();
+
+        assert_eq!(blocks.len(), 1);
+        let block = &blocks[0];
+        assert_eq!(block.target_address, 0x4000);
+        assert_eq!(block.block_no, 1);
+        assert_eq!(block.num_blocks, 2);
+        assert_eq!(block.data.len(), 256);
+        assert_eq!(block.data[255], 255);
+        assert_eq!(block.family_id(), Some(FamilyId::ATSAMD51));
+        assert!(block.is_main_flash());
+    }
+
+    #[test]
+    fn family_id_needs_flag() {
+        let blocks = parse(&fixture(FLAG_NOT_MAIN_FLASH, 1024)).unwrap();
+
+        assert_eq!(blocks[0].family_id(), None);
+        assert_eq!(blocks[0].file_size_or_family_id, 1024);
+        assert!(!blocks[0].is_main_flash());
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut bytes = fixture(0, 0);
+        bytes[511] = 0;
+        assert!(matches!(
+            parse(&bytes),
+            Err(UtilError::InvalidUf2 { block: 1, .. })
+        ));
+
+        let mut bytes = fixture(0, 0);
+        bytes[0] = 0;
+        assert!(parse(&bytes).is_err());
+    }
+
+    #[test]
+    fn bad_length() {
+        let bytes = fixture(0, 0);
+        assert!(parse(&bytes[..500]).is_err());
+
+        let mut bytes = fixture(0, 0);
+        bytes[16] = 0xFF;
+        bytes[17] = 0x01;
+        assert!(parse(&bytes).is_err());
+
+        // the third block in the file
+        let bytes = [fixture(0, 0), fixture(0, 0)].concat();
+        assert!(matches!(
+            Block::from_bytes(&bytes[..500], 2),
+            Err(UtilError::InvalidUf2 { block: 2, .. })
+        ));
+        assert_eq!(parse(&bytes).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn round_trip() {
+        let binary: Vec<u8> = (0..600).map(|i| i as u8).collect();
+
+        let blocks = from_bin(&binary, 0x2000, 256, Some(FamilyId::NRF52840)).unwrap();
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[2].target_address, 0x2200);
+        assert_eq!(blocks[2].data.len(), 88);
+
+        let bytes = to_bytes(&blocks).unwrap();
+        assert_eq!(bytes.len(), 3 * BLOCK_SIZE);
+        assert_eq!(&bytes[512..][..8], &fixture(0, 0)[..8]);
+
+        let parsed = parse(&bytes).unwrap();
+        assert_eq!(parsed, blocks);
+        assert_eq!(parsed[0].family_id(), Some(FamilyId::NRF52840));
+    }
+
+    #[test]
+    fn from_elf_file() {
+        use crate::utils::elf_to_bin;
+
+        let path: PathBuf = [
+            env!("CARGO_MANIFEST_DIR"),
+            "src/utils/testdata/blinky_1.47.0",
+        ]
+        .iter()
+        .collect();
+        let (binary, address) = elf_to_bin(path.clone()).unwrap();
+
+        let blocks = from_file(path, None, Some(FamilyId::ATSAMD51)).unwrap();
+        assert_eq!(blocks[0].target_address, address);
+        assert_eq!(blocks[0].family_id(), Some(FamilyId::ATSAMD51));
+        assert_eq!(
+            blocks
+                .iter()
+                .flat_map(|b| b.data.clone())
+                .collect::<Vec<_>>(),
+            binary
+        );
+
+        let bin = std::env::temp_dir().join(format!("hf2_uf2_raw_{}.bin", std::process::id()));
+        std::fs::write(&bin, &binary).unwrap();
+        assert!(matches!(
+            from_file(bin.clone(), None, None),
+            Err(UtilError::MissingAddress)
+        ));
+        assert_eq!(
+            from_file(bin.clone(), Some(0x4000), None).unwrap()[1].target_address,
+            0x4100
+        );
+        std::fs::remove_file(bin).unwrap();
+    }
+
+    #[test]
+    fn image_of_main_flash_blocks() {
+        let mut blocks = from_bin(&[1; 300], 0x1100, 256, None).unwrap();
+        blocks[1].flags |= FLAG_NOT_MAIN_FLASH;
+
+        let image = to_image(&blocks).unwrap();
+        assert_eq!(image.len(), 256);
+        assert_eq!(image.address(), Some(0x1100));
+
+        let mut image = Image::from_bin(&[1; 300], 0x1100).unwrap();
+        image.add(0x8000, &[2; 10]).unwrap();
+        let blocks = from_image(&image, 256, None).unwrap();
+        assert_eq!(
+            blocks
+                .iter()
+                .map(|block| (block.block_no, block.num_blocks, block.target_address))
+                .collect::<Vec<_>>(),
+            vec![(0, 3, 0x1100), (1, 3, 0x1200), (2, 3, 0x8000)]
+        );
+        assert_eq!(to_image(&blocks).unwrap(), image);
+    }
+}
@@ -1,10 +1,10 @@
-use crate::command::{rx, xmit, Command};
-use crate::Error;
+use crate::command::{send, Command};
+use crate::{Error, ReadWrite};
 use scroll::Pwrite;
 
 ///Write a single page of flash memory. Empty tuple response.
 pub fn write_flash_page(
-    d: &hidapi::HidDevice,
+    d: &impl ReadWrite,
     target_address: u32,
     data: Vec<u8>,
 ) -> Result<(), Error> {
@@ -16,7 +16,7 @@ pub fn write_flash_page(
         buffer.gwrite_with(i, &mut offset, scroll::LE)?;
     }
 
-    xmit(Command::new(0x0006, 0, buffer), d)?;
-
-    rx(d).map(|_| ())
+    send(Command::new(0x0006, d.hf2_next_tag(), buffer), d)
+        .map(|_| ())
+        .map_err(|e| e.context(0x0006, Some(target_address)))
 }
@@ -1,10 +1,10 @@
-use crate::command::{rx, xmit, Command};
-use crate::Error;
+use crate::command::{send, Command};
+use crate::{Error, ReadWrite};
 use scroll::Pwrite;
 
 ///Dual of READ WORDS, with the same constraints. Empty tuple response.
 pub fn write_words(
-    d: &hidapi::HidDevice,
+    d: &impl ReadWrite,
     target_address: u32,
     num_words: u32,
     words: Vec<u32>,
@@ -18,7 +18,7 @@ pub fn write_words(
         buffer.gwrite_with(i, &mut offset, scroll::LE)?;
     }
 
-    xmit(Command::new(0x0009, 0, buffer), d)?;
-
-    rx(d).map(|_| ())
+    send(Command::new(0x0009, d.hf2_next_tag(), buffer), d)
+        .map(|_| ())
+        .map_err(|e| e.context(0x0009, Some(target_address)))
 }